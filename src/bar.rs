@@ -0,0 +1,115 @@
+use std::io::{self, Write};
+use std::process;
+use std::sync::mpsc::Receiver;
+
+use serde_json::json;
+
+use crate::config::Config;
+use crate::mpris_mediaplayer2::PlayerMetadata;
+use crate::nowplaying::{TrackEvent, TrackEventKind};
+use crate::template;
+
+#[derive(Clone, Copy, PartialEq)]
+pub enum BarFormat {
+    //waybar custom module JSON (text, tooltip, class, alt, percentage)
+    Waybar,
+    //the rendered template only, e.g. for polybar or i3blocks
+    Plain
+}
+
+impl BarFormat {
+    //--bar [--plain]
+    pub fn from_args(args: &[String]) -> Result<BarFormat, String> {
+        let mut format = BarFormat::Waybar;
+        for arg in args {
+            match arg.as_str() {
+                "--plain" => format = BarFormat::Plain,
+                "--json" => format = BarFormat::Waybar,
+                _ => return Err(format!("unknown option: {arg}\nusage: nowplaying_1lt --bar [--plain|--json]"))
+            }
+        }
+        Ok(format)
+    }
+}
+
+//Prints one line per change to stdout for status bars
+pub struct BarOutput {
+    format: BarFormat,
+    template: String,
+    config: Config
+}
+
+impl BarOutput {
+    pub fn new(config: &Config, format: BarFormat) -> BarOutput {
+        let template = match config.template.as_str() {
+            "" => "{artist} - {title}".to_string(),
+            template => template.to_string()
+        };
+        BarOutput {
+            format,
+            template,
+            config: config.clone()
+        }
+    }
+
+    pub fn start(self, rx: Receiver<TrackEvent>) {
+        //start with an empty module until something plays
+        self.print(&self.line(None, &PlayerMetadata::new()));
+        let mut previous = PlayerMetadata::new();
+        let mut current = PlayerMetadata::new();
+        for event in rx {
+            if event.kind == TrackEventKind::Changed {
+                previous = std::mem::replace(&mut current, event.metadata.clone());
+            }
+            self.print(&self.line(Some(&event), &previous));
+        }
+    }
+
+    fn line(&self, event: Option<&TrackEvent>, previous: &PlayerMetadata) -> String {
+        let event = match event {
+            Some(event) if event.kind != TrackEventKind::Stopped => event,
+            _ => return match self.format {
+                BarFormat::Waybar => json!({"text": "", "tooltip": "", "class": "stopped", "alt": "stopped"}).to_string(),
+                BarFormat::Plain => String::new()
+            }
+        };
+        if self.format == BarFormat::Plain {
+            return template::render(&self.template, &self.config, &event.player, &event.metadata, previous)
+        }
+
+        //waybar interprets text and tooltip as pango markup
+        let metadata = markup_escape_metadata(&event.metadata);
+        let text = template::render(&self.template, &self.config, &markup_escape(&event.player), &metadata, &markup_escape_metadata(previous));
+        let mut tooltip = vec![template::short_description(&self.config, &metadata)];
+        if self.config.display_album && metadata.album != "" {
+            tooltip.push(metadata.album.clone());
+        }
+        tooltip.push(markup_escape(&event.player));
+        let status = event.kind.status();
+        let mut line = json!({"text": text, "tooltip": tooltip.join("\n"), "class": status, "alt": status});
+        if event.metadata.length_ms > 0 {
+            line["percentage"] = json!((event.position_ms * 100 / event.metadata.length_ms).min(100));
+        }
+        line.to_string()
+    }
+
+    //a closed pipe means the bar went away, so exit quietly instead of panicking
+    fn print(&self, line: &str) {
+        let mut stdout = io::stdout().lock();
+        if writeln!(stdout, "{line}").and_then(|()| stdout.flush()).is_err() {
+            process::exit(0);
+        }
+    }
+}
+
+fn markup_escape(value: &str) -> String {
+    value.replace('&', "&amp;").replace('<', "&lt;").replace('>', "&gt;")
+}
+
+fn markup_escape_metadata(metadata: &PlayerMetadata) -> PlayerMetadata {
+    let mut escaped = metadata.clone();
+    escaped.artist = markup_escape(&metadata.artist);
+    escaped.title = markup_escape(&metadata.title);
+    escaped.album = markup_escape(&metadata.album);
+    escaped
+}
@@ -0,0 +1,547 @@
+use std::collections::BTreeMap;
+use std::path::Path;
+use std::process;
+
+use serde::Serialize;
+use serde_json::json;
+
+use crate::config::Config;
+use crate::config_format::{self, Format};
+use crate::config_migration;
+use crate::dbus_service;
+use crate::history::PlayHistory;
+use crate::mpris_mediaplayer2::{self, RawMetadataValue};
+use crate::nowplaying;
+use crate::setlist;
+use crate::stats;
+use crate::template;
+
+//exit codes
+pub const EXIT_OK: i32 = 0;
+pub const EXIT_ERROR: i32 = 1;
+pub const EXIT_USAGE: i32 = 2;
+pub const EXIT_NOTHING_PLAYING: i32 = 3;
+
+const DEFAULT_HISTORY_LIMIT: usize = 10;
+
+pub const USAGE: &str = "\
+usage: nowplaying_1lt [--config <path>] [--data-dir <path>] [--profile <name>] [--quiet|--verbose] [<command>]
+
+commands:
+  run                      start the service (default)
+  --bar [--plain]          print one line per change for waybar/polybar
+  status [--json]          print the current track once (exit code 3 if nothing is playing)
+  control <action> [--player <name>]
+                           play, pause, play_pause, next, previous or stop
+  list-players [--json]    show every MPRIS player with its metadata and whether it would be picked
+  history [--limit <n>] [--json]
+                           print the last played tracks
+  setlist [options]        export the play history as a setlist
+  stats [options]          print listening statistics
+  config show|check|migrate|new-keys|convert|init|path
+                           print, validate, upgrade, list missing keys of, convert to TOML/JSON,
+                           create or locate the config file
+  help, version
+
+exit codes: 0 ok, 1 error, 2 invalid usage, 3 nothing playing";
+
+#[derive(Clone, Copy, PartialEq)]
+pub enum Verbosity {
+    Quiet,
+    Normal,
+    Verbose
+}
+
+pub struct GlobalOptions {
+    pub config_path: Option<String>,
+    pub data_directory: Option<String>,
+    pub profile: Option<String>,
+    pub verbosity: Verbosity
+}
+
+impl GlobalOptions {
+    //Takes --config <path>, --data-dir <path>, --profile <name>, --quiet and --verbose from the start of the arguments,
+    //the command and everything after it is returned, so commands can use the same short options
+    pub fn from_args(args: Vec<String>) -> Result<(GlobalOptions, Vec<String>), String> {
+        let mut options = GlobalOptions { config_path: None, data_directory: None, profile: None, verbosity: Verbosity::Normal };
+        let mut rest = Vec::new();
+        let mut args = args.into_iter();
+        while let Some(arg) = args.next() {
+            match arg.as_str() {
+                "--config" | "-c" => match args.next() {
+                    Some(path) => options.config_path = Some(path),
+                    None => return Err("--config needs a path".to_string())
+                },
+                "--data-dir" => match args.next() {
+                    Some(path) => options.data_directory = Some(path),
+                    None => return Err("--data-dir needs a path".to_string())
+                },
+                "--profile" => match args.next() {
+                    Some(name) => options.profile = Some(name),
+                    None => return Err("--profile needs a name".to_string())
+                },
+                "--quiet" | "-q" => options.verbosity = Verbosity::Quiet,
+                "--verbose" | "-v" => options.verbosity = Verbosity::Verbose,
+                _ => match arg.split_once('=') {
+                    Some(("--config", path)) => options.config_path = Some(path.to_string()),
+                    Some(("--data-dir", path)) => options.data_directory = Some(path.to_string()),
+                    Some(("--profile", name)) => options.profile = Some(name.to_string()),
+                    _ => {
+                        rest.push(arg);
+                        rest.extend(args);
+                        break
+                    }
+                }
+            }
+        }
+        Ok((options, rest))
+    }
+
+    pub fn config_path(&self) -> String {
+        match &self.config_path {
+            Some(path) => path.clone(),
+            None => Config::get_config_location()
+        }
+    }
+
+    pub fn load_config(&self) -> Config {
+        Config::load(self.config_path(), self.profile.as_deref(), false)
+    }
+
+    //like load_config, an outdated config file is migrated first if a migration step changes its keys
+    pub fn load_service_config(&self) -> Config {
+        Config::load(self.config_path(), self.profile.as_deref(), true)
+    }
+}
+
+pub fn usage_error(error: &str) -> ! {
+    eprintln!("{error}\n\n{USAGE}");
+    process::exit(EXIT_USAGE);
+}
+
+pub fn list_players(options: &GlobalOptions, args: &[String]) {
+    let mut json_output = false;
+    for arg in args {
+        match arg.as_str() {
+            "--json" => json_output = true,
+            _ => usage_error(&format!("unknown option: {arg}"))
+        }
+    }
+    let config = options.load_config();
+    let mediaplayers = match mpris_mediaplayer2::get_media_players() {
+        Ok(mediaplayers) => mediaplayers,
+        Err(error) => {
+            eprintln!("Cannot list mediaplayers: {error}");
+            process::exit(EXIT_ERROR);
+        }
+    };
+
+    //a running service may have a player selected over D-Bus, which the selection prefers
+    let selected_player = match config.dbus_enabled {
+        true => dbus_service::get_selected_player(&config.dbus_name),
+        false => None
+    };
+    let picked_player = nowplaying::select_player(&mediaplayers, selected_player.as_deref(), &config.player_priority).unwrap_or(None);
+
+    let players: Vec<PlayerDetails> = mediaplayers.iter().map(|mediaplayer| {
+        let mut errors = Vec::new();
+        PlayerDetails {
+            bus_name: format!("org.mpris.MediaPlayer2.{mediaplayer}"),
+            player: mediaplayer.clone(),
+            identity: mpris_mediaplayer2::get_media_player_identity(mediaplayer).map_err(|error| errors.push(format!("identity: {error}"))).ok(),
+            status: mpris_mediaplayer2::get_media_player_playback_status_text(mediaplayer).map_err(|error| errors.push(format!("status: {error}"))).ok(),
+            position_ms: mpris_mediaplayer2::get_media_player_position(mediaplayer).map(|position| position / 1000).map_err(|error| errors.push(format!("position: {error}"))).ok(),
+            metadata: mpris_mediaplayer2::get_media_player_raw_metadata(mediaplayer).map_err(|error| errors.push(format!("metadata: {error}"))).unwrap_or_default(),
+            selected: selected_player.as_ref() == Some(mediaplayer),
+            picked: picked_player.as_ref() == Some(mediaplayer),
+            errors
+        }
+    }).collect();
+
+    if json_output {
+        println!("{}", json!({"selected_player": selected_player, "picked_player": picked_player, "players": players}));
+        process::exit(EXIT_OK);
+    }
+    if players.is_empty() {
+        println!("no mediaplayers found");
+    }
+    let unknown = || "?".to_string();
+    for player in &players {
+        println!("{}", player.bus_name);
+        println!("  identity:  {}", player.identity.clone().unwrap_or_else(unknown));
+        println!("  status:    {}", player.status.clone().unwrap_or_else(unknown));
+        println!("  position:  {}", player.position_ms.map(format_position).unwrap_or_else(unknown));
+        println!("  picked:    {}{}", if player.picked { "yes" } else { "no" }, if player.selected { " (selected over D-Bus)" } else { "" });
+        println!("  metadata:");
+        for (key, value) in &player.metadata {
+            println!("    {:<24} {:<6} {}", key, value.signature, value.value);
+        }
+        for error in &player.errors {
+            println!("  error:     {error}");
+        }
+        println!();
+    }
+    process::exit(EXIT_OK);
+}
+
+//everything list-players knows about one player
+#[derive(Serialize)]
+struct PlayerDetails {
+    bus_name: String,
+    player: String,
+    identity: Option<String>,
+    status: Option<String>,
+    position_ms: Option<i64>,
+    metadata: BTreeMap<String, RawMetadataValue>,
+    //chosen over D-Bus on the running service
+    selected: bool,
+    //the player the service would display right now
+    picked: bool,
+    errors: Vec<String>
+}
+
+fn format_position(position_ms: i64) -> String {
+    let seconds = position_ms.max(0) / 1000;
+    format!("{}:{:02}", seconds / 60, seconds % 60)
+}
+
+pub fn status(options: &GlobalOptions, args: &[String]) {
+    let mut json_output = false;
+    for arg in args {
+        match arg.as_str() {
+            "--json" => json_output = true,
+            _ => usage_error(&format!("unknown option: {arg}"))
+        }
+    }
+    let config = options.load_config();
+    let mediaplayers = match mpris_mediaplayer2::get_media_players() {
+        Ok(mediaplayers) => mediaplayers,
+        Err(error) => {
+            eprintln!("Cannot list mediaplayers: {error}");
+            process::exit(EXIT_ERROR);
+        }
+    };
+    let mediaplayer = match nowplaying::select_player(&mediaplayers, None, &config.player_priority) {
+        Ok(Some(mediaplayer)) => mediaplayer,
+        Ok(None) => {
+            if json_output {
+                println!("{}", json!({"status": "stopped"}));
+            } else if options.verbosity != Verbosity::Quiet {
+                println!("nothing is playing");
+            }
+            process::exit(EXIT_NOTHING_PLAYING);
+        }
+        Err(error) => {
+            eprintln!("Cannot get mediaplayer playback status: {error}");
+            process::exit(EXIT_ERROR);
+        }
+    };
+    let metadata = match mpris_mediaplayer2::PlayerMetadata::new().update_metadata_of_player(mediaplayer.clone()) {
+        Ok(metadata) => metadata,
+        Err(error) => {
+            eprintln!("Cannot get mediaplayer metadata: {error}");
+            process::exit(EXIT_ERROR);
+        }
+    };
+
+    if json_output {
+        println!("{}", json!({"status": "playing", "player": mediaplayer, "metadata": metadata}));
+    } else {
+        let status_template = match config.template.as_str() {
+            "" => "{artist} - {title}",
+            status_template => status_template
+        };
+        println!("{}", template::render(status_template, &config, &mediaplayer, &metadata, &mpris_mediaplayer2::PlayerMetadata::new()));
+    }
+    process::exit(EXIT_OK);
+}
+
+pub fn control(options: &GlobalOptions, args: &[String]) {
+    let mut action: Option<&str> = None;
+    let mut player: Option<String> = None;
+    let mut args = args.iter();
+    while let Some(arg) = args.next() {
+        match arg.as_str() {
+            "--player" => match args.next() {
+                Some(name) => player = Some(name.trim_start_matches("org.mpris.MediaPlayer2.").to_string()),
+                None => usage_error("--player needs a name")
+            },
+            _ if action.is_none() && !arg.starts_with('-') => action = Some(arg),
+            _ => usage_error(&format!("unknown option: {arg}"))
+        }
+    }
+    let action = match action {
+        Some(action) => action,
+        None => usage_error("control needs an action")
+    };
+
+    //without --player: the playing player, otherwise the first one
+    let player = match player {
+        Some(player) => player,
+        None => {
+            let config = options.load_config();
+            let mediaplayers = mpris_mediaplayer2::get_media_players().unwrap_or_default();
+            match nowplaying::select_player(&mediaplayers, None, &config.player_priority) {
+                Ok(Some(mediaplayer)) => mediaplayer,
+                _ => match mediaplayers.first() {
+                    Some(mediaplayer) => mediaplayer.clone(),
+                    None => {
+                        eprintln!("no mediaplayer found");
+                        process::exit(EXIT_ERROR);
+                    }
+                }
+            }
+        }
+    };
+    match mpris_mediaplayer2::control_media_player(&player, action) {
+        Ok(()) => {
+            if options.verbosity == Verbosity::Verbose {
+                println!("{action}: {player}");
+            }
+            process::exit(EXIT_OK);
+        }
+        Err(error) => {
+            eprintln!("{error}");
+            process::exit(EXIT_ERROR);
+        }
+    }
+}
+
+pub fn history(options: &GlobalOptions, args: &[String]) {
+    let mut limit = DEFAULT_HISTORY_LIMIT;
+    let mut json_output = false;
+    let mut args = args.iter();
+    while let Some(arg) = args.next() {
+        match arg.as_str() {
+            "--limit" | "-n" => match args.next().and_then(|value| value.parse::<usize>().ok()) {
+                Some(value) => limit = value,
+                None => usage_error("--limit needs a positive number")
+            },
+            "--json" => json_output = true,
+            _ => usage_error(&format!("unknown option: {arg}"))
+        }
+    }
+    let config = options.load_config();
+    let history = PlayHistory::new(&config, &config.work_directory());
+    let records = match history.last_records(limit) {
+        Ok(records) => records,
+        Err(error) => {
+            eprintln!("Cannot read play history: {:?}", error);
+            process::exit(EXIT_ERROR);
+        }
+    };
+
+    if json_output {
+        println!("{}", json!(records));
+    } else {
+        for record in &records {
+            println!("{}  {} ({})", setlist::format_local_time(record.started), template::short_description(&config, &record.metadata), record.player);
+        }
+    }
+    process::exit(EXIT_OK);
+}
+
+pub fn export_setlist(global_options: &GlobalOptions, args: &[String]) {
+    let options = match setlist::SetlistOptions::from_args(args) {
+        Ok(options) => options,
+        Err(error) => {
+            eprintln!("{error}");
+            process::exit(EXIT_USAGE);
+        }
+    };
+    let config = global_options.load_config();
+    let history = PlayHistory::new(&config, &config.work_directory());
+    match history.records() {
+        Ok(records) => print!("{}", setlist::export(&records, &options)),
+        Err(error) => {
+            eprintln!("Cannot read play history: {:?}", error);
+            process::exit(EXIT_ERROR);
+        }
+    };
+    process::exit(EXIT_OK);
+}
+
+pub fn print_stats(global_options: &GlobalOptions, args: &[String]) {
+    let options = match stats::StatsOptions::from_args(args) {
+        Ok(options) => options,
+        Err(error) => {
+            eprintln!("{error}");
+            process::exit(EXIT_USAGE);
+        }
+    };
+    let config = global_options.load_config();
+    let history = PlayHistory::new(&config, &config.work_directory());
+    let records = match history.records() {
+        Ok(records) => records,
+        Err(error) => {
+            eprintln!("Cannot read play history: {:?}", error);
+            process::exit(EXIT_ERROR);
+        }
+    };
+    let stats = stats::compute(&records, &options);
+    if options.json {
+        match serde_json::to_string_pretty(&stats) {
+            Ok(json) => println!("{json}"),
+            Err(error) => {
+                eprintln!("Cannot serialize stats: {error}");
+                process::exit(EXIT_ERROR);
+            }
+        };
+    } else {
+        print!("{}", stats::format_text(&stats));
+    }
+    process::exit(EXIT_OK);
+}
+
+pub fn config_command(options: &GlobalOptions, args: &[String]) {
+    let config_path = options.config_path();
+    let exists = Path::new(&config_path).exists();
+    match (args.first().map(|arg| arg.as_str()), args.get(1).map(|arg| arg.as_str())) {
+        (Some("path"), None) => println!("{config_path}"),
+        (Some("show"), None) => {
+            if !exists {
+                eprintln!("{config_path} does not exist, the default values are used");
+                process::exit(EXIT_ERROR);
+            }
+            let file = match config_format::read(&config_path) {
+                Ok(file) => file,
+                Err(error) => {
+                    eprintln!("Cannot read {config_path}: {error}");
+                    process::exit(EXIT_ERROR);
+                }
+            };
+            for group in file.config_groups {
+                println!("[{}]", group.group_name());
+                for attribute in group.config_attributes() {
+                    println!("{} = {}", attribute.name, mask_secret(&attribute.name, &attribute.value));
+                }
+                println!();
+            }
+        }
+        (Some("check"), None) => {
+            let (config, mut report) = Config::check(&config_path, options.profile.as_deref());
+            //profiles that are not active are checked too, only their own problems are shown
+            for profile in config.profiles.iter().filter(|profile| **profile != config.profile) {
+                let (_config, profile_report) = Config::check(&config_path, Some(profile));
+                for (issues, profile_issues) in [(&mut report.errors, profile_report.errors), (&mut report.warnings, profile_report.warnings)] {
+                    let known: Vec<String> = issues.iter().map(|issue| issue.to_string()).collect();
+                    for mut issue in profile_issues.into_iter().filter(|issue| !known.contains(&issue.to_string())) {
+                        issue.message = format!("profile {profile}: {}", issue.message);
+                        issues.push(issue);
+                    }
+                }
+            }
+            for error in &report.errors {
+                println!("error: {error}");
+            }
+            for warning in &report.warnings {
+                println!("warning: {warning}");
+            }
+            if !report.errors.is_empty() {
+                eprintln!("{config_path}: {} error(s), {} warning(s)", report.errors.len(), report.warnings.len());
+                process::exit(EXIT_ERROR);
+            }
+            println!("{config_path}: ok, {} warning(s)", report.warnings.len());
+        }
+        (Some("migrate"), None) => {
+            if !exists {
+                eprintln!("{config_path} does not exist");
+                process::exit(EXIT_ERROR);
+            }
+            match config_migration::migrate(&config_path, false) {
+                Ok(Some(migration)) => {
+                    println!("{}", migration.summary);
+                    for change in migration.changes {
+                        println!("  {change}");
+                    }
+                }
+                Ok(None) => println!("{config_path} is up to date"),
+                Err(error) => {
+                    eprintln!("Cannot migrate: {error}");
+                    process::exit(EXIT_ERROR);
+                }
+            }
+        }
+        (Some("new-keys"), None) => {
+            if !exists {
+                eprintln!("{config_path} does not exist");
+                process::exit(EXIT_ERROR);
+            }
+            match config_migration::new_keys(&config_path) {
+                Ok(text) => print!("{text}"),
+                Err(error) => {
+                    eprintln!("{error}");
+                    process::exit(EXIT_ERROR);
+                }
+            }
+        }
+        (Some("convert"), _) => convert_config(&config_path, &args[1..]),
+        (Some("init"), force) => {
+            if force.is_some() && force != Some("--force") {
+                usage_error(&format!("unknown option: {}", force.unwrap_or_default()));
+            }
+            if exists && force.is_none() {
+                eprintln!("{config_path} already exists, use --force to overwrite it");
+                process::exit(EXIT_ERROR);
+            }
+            Config::write_defaults(&config_path);
+        }
+        _ => usage_error("usage: nowplaying_1lt config show|check|migrate|new-keys|convert [toml|json] [--force]|init [--force]|path")
+    }
+    process::exit(EXIT_OK);
+}
+
+//Writes the config file as TOML (default) or JSON next to it, the original file is kept
+fn convert_config(config_path: &String, args: &[String]) {
+    let mut format = Format::Toml;
+    let mut force = false;
+    for arg in args {
+        match (arg.as_str(), Format::from_name(arg)) {
+            ("--force", _) => force = true,
+            (_, Some(Format::Legacy)) => usage_error("the legacy format cannot be written, use toml or json"),
+            (_, Some(target_format)) => format = target_format,
+            (arg, None) => usage_error(&format!("unknown option: {arg}"))
+        }
+    }
+    if !Path::new(config_path).exists() {
+        eprintln!("{config_path} does not exist");
+        process::exit(EXIT_ERROR);
+    }
+    if Format::of(config_path) == format {
+        eprintln!("{config_path} already is a {} file", format.name());
+        process::exit(EXIT_ERROR);
+    }
+    let target = Path::new(config_path).with_extension(format.name()).display().to_string();
+    if Path::new(&target).exists() && !force {
+        eprintln!("{target} already exists, use --force to overwrite it");
+        process::exit(EXIT_ERROR);
+    }
+    let file = match config_format::read(config_path) {
+        Ok(file) => file,
+        Err(error) => {
+            eprintln!("Cannot read {config_path}: {error}");
+            process::exit(EXIT_ERROR);
+        }
+    };
+    if let Err(error) = config_format::write(&target, &file) {
+        eprintln!("Cannot write {target}: {error}");
+        process::exit(EXIT_ERROR);
+    }
+    println!("converted {config_path} to {target}");
+    match Config::get_config_location() == target {
+        true => println!("{target} is read from now on, {config_path} is kept but no longer used"),
+        false => println!("use it with --config {target} or $NOWPLAYING_CONFIG")
+    }
+}
+
+//hides passwords, tokens, keys and authorization headers in config show
+fn mask_secret(name: &str, value: &str) -> String {
+    let secret = ["password", "token", "secret", "session_key", "api_key"].iter().any(|secret| name.contains(secret));
+    if secret && value != "" {
+        return "********".to_string()
+    }
+    match value.split_once(':') {
+        Some((header, _value)) if name.starts_with("header") && header.trim().eq_ignore_ascii_case("authorization") => format!("{header}: ********"),
+        _ => value.to_string()
+    }
+}
@@ -1,150 +1,786 @@
 use std::time;
 use std::env;
 use std::fs;
-use std::path::Path;
+use std::path::{Path, PathBuf};
+use std::process;
 
 use config_1lt::data::{config_file::ConfigFile, config_attribute::ConfigAttribute};
-use config_1lt::file::{read::read_config_file, write::write_config_file};
 
-#[derive(Clone)]
+use crate::cli::EXIT_ERROR;
+use crate::config_format;
+use crate::config_migration::{self, CONFIG_VERSION};
+use crate::config_reader::{self, ConfigIssue, ConfigReport, GroupReader, LineIndex, Overrides};
+use crate::paths;
+
+const KNOWN_GROUPS: &[&str] = &["general", "web_files", "history", "hooks", "webhook", "mqtt", "obs", "discord", "irc", "lastfm", "listenbrainz", "notifications", "dbus", "socket", "logging"];
+
+#[derive(Clone, PartialEq)]
 pub struct Config {
     pub nowplaying_path: String,
+    pub template: String,
     pub display_artist: bool,
     pub display_album: bool,
     pub display_title: bool,
     pub sleep_duration: time::Duration,
+    //what now_playing.txt shows after SIGINT/SIGTERM: clear, offline (offline_template) or keep
+    pub on_exit: String,
+    pub offline_template: String,
     pub port: String,
     pub web_files: Vec<String>,
-    pub public: bool
+    //web files are looked up in web_display/themes/<web_theme> first, empty: no theme
+    pub web_theme: String,
+    pub public: bool,
+    //players preferred when more than one is playing, matched by their MPRIS name (vlc also matches vlc.instance123)
+    pub player_priority: Vec<String>,
+    //active [profile.<name>], empty for none, and all profiles in the file
+    pub profile: String,
+    pub profiles: Vec<String>,
+    pub history_enabled: bool,
+    pub history_max_size_kb: u64,
+    pub history_max_files: u32,
+    pub hooks: HookConfig,
+    pub webhooks: Vec<WebhookConfig>,
+    pub mqtt: MqttConfig,
+    pub obs: ObsConfig,
+    pub discord: DiscordConfig,
+    pub irc: IrcConfig,
+    pub lastfm: LastFmConfig,
+    pub listenbrainz: ListenBrainzConfig,
+    pub notifications: NotificationConfig,
+    pub dbus_enabled: bool,
+    pub dbus_name: String,
+    pub socket_enabled: bool,
+    //empty: $XDG_RUNTIME_DIR/1lt_nowplaying.sock
+    pub socket_path: String,
+    //error, warn, info, debug or trace
+    pub log_level: String,
+    //comma separated module=level pairs, e.g. web_display=debug,irc=warn
+    pub log_modules: String,
+    pub log_timestamps: bool,
+    //auto (journald when started by systemd), text or journald
+    pub log_format: String,
+    //nowplaying.log in the work directory
+    pub log_file: bool,
+    pub log_file_max_size_kb: u64,
+    pub log_file_max_files: u32
+}
+
+#[derive(Clone, PartialEq)]
+pub struct WebhookConfig {
+    pub url: String,
+    pub headers: Vec<(String, String)>,
+    pub body: String,
+    pub retries: u32,
+    pub timeout: time::Duration
+}
+
+impl WebhookConfig {
+    pub fn new(url: String) -> WebhookConfig {
+        WebhookConfig {
+            url,
+            headers: Vec::new(),
+            body: "{json}".to_string(),
+            retries: 3,
+            timeout: time::Duration::from_millis(5000)
+        }
+    }
+}
+
+#[derive(Clone, PartialEq)]
+pub struct HookConfig {
+    pub commands: Vec<String>,
+    pub on_pause: bool,
+    pub on_stop: bool,
+    pub timeout: time::Duration
+}
+
+impl HookConfig {
+    pub fn defaults() -> HookConfig {
+        HookConfig {
+            commands: Vec::new(),
+            on_pause: false,
+            on_stop: false,
+            timeout: time::Duration::from_millis(10000)
+        }
+    }
+}
+
+#[derive(Clone, PartialEq)]
+pub struct MqttConfig {
+    pub enabled: bool,
+    pub host: String,
+    pub port: u16,
+    pub client_id: String,
+    pub username: String,
+    pub password: String,
+    pub topic_prefix: String,
+    pub commands: bool,
+    pub keep_alive: time::Duration
+}
+
+impl MqttConfig {
+    pub fn defaults() -> MqttConfig {
+        MqttConfig {
+            enabled: false,
+            host: "127.0.0.1".to_string(),
+            port: 1883,
+            client_id: "1lt_nowplaying".to_string(),
+            username: String::new(),
+            password: String::new(),
+            topic_prefix: "nowplaying".to_string(),
+            commands: false,
+            keep_alive: time::Duration::from_secs(60)
+        }
+    }
+}
+
+#[derive(Clone, PartialEq)]
+pub struct ObsConfig {
+    pub enabled: bool,
+    pub url: String,
+    pub password: String,
+    //(source name, template), an empty template uses the general template
+    pub text_sources: Vec<(String, String)>,
+    pub image_source: String,
+    pub visibility_scene: String,
+    pub visibility_source: String
+}
+
+impl ObsConfig {
+    pub fn defaults() -> ObsConfig {
+        ObsConfig {
+            enabled: false,
+            url: "ws://127.0.0.1:4455".to_string(),
+            password: String::new(),
+            text_sources: Vec::new(),
+            image_source: String::new(),
+            visibility_scene: String::new(),
+            visibility_source: String::new()
+        }
+    }
+}
+
+#[derive(Clone, PartialEq)]
+pub struct DiscordConfig {
+    pub enabled: bool,
+    pub client_id: String
+}
+
+impl DiscordConfig {
+    pub fn defaults() -> DiscordConfig {
+        DiscordConfig {
+            enabled: false,
+            client_id: String::new()
+        }
+    }
+}
+
+#[derive(Clone, PartialEq)]
+pub struct IrcConfig {
+    pub enabled: bool,
+    pub server: String,
+    pub port: u16,
+    pub nick: String,
+    pub password: String,
+    pub channels: Vec<String>,
+    pub song_command: String,
+    pub song_template: String,
+    pub last_song_command: String,
+    pub last_song_template: String,
+    pub command_cooldown: time::Duration,
+    pub announce: bool,
+    pub announce_template: String,
+    pub announce_interval: time::Duration
+}
+
+impl IrcConfig {
+    pub fn defaults() -> IrcConfig {
+        IrcConfig {
+            enabled: false,
+            server: "irc.chat.twitch.tv".to_string(),
+            port: 6667,
+            nick: String::new(),
+            password: String::new(),
+            channels: Vec::new(),
+            song_command: "!song".to_string(),
+            song_template: "Now playing: {artist} - {title}".to_string(),
+            last_song_command: "!lastsong".to_string(),
+            last_song_template: "Last song: {artist} - {title}".to_string(),
+            command_cooldown: time::Duration::from_secs(5),
+            announce: false,
+            announce_template: "Now playing: {artist} - {title}".to_string(),
+            announce_interval: time::Duration::from_secs(30)
+        }
+    }
+}
+
+#[derive(Clone, PartialEq)]
+pub struct LastFmConfig {
+    pub enabled: bool,
+    pub base_url: String,
+    pub api_key: String,
+    pub api_secret: String,
+    pub session_key: String,
+    pub username: String,
+    pub password: String
+}
+
+impl LastFmConfig {
+    pub fn defaults() -> LastFmConfig {
+        LastFmConfig {
+            enabled: false,
+            base_url: "https://ws.audioscrobbler.com/2.0/".to_string(),
+            api_key: String::new(),
+            api_secret: String::new(),
+            session_key: String::new(),
+            username: String::new(),
+            password: String::new()
+        }
+    }
+}
+
+#[derive(Clone, PartialEq)]
+pub struct ListenBrainzConfig {
+    pub enabled: bool,
+    pub base_url: String,
+    pub token: String
+}
+
+impl ListenBrainzConfig {
+    pub fn defaults() -> ListenBrainzConfig {
+        ListenBrainzConfig {
+            enabled: false,
+            base_url: "https://api.listenbrainz.org".to_string(),
+            token: String::new()
+        }
+    }
+}
+
+#[derive(Clone, PartialEq)]
+pub struct NotificationConfig {
+    pub enabled: bool,
+    pub summary: String,
+    //\n in the body starts a new line
+    pub body: String,
+    //-1 uses the default of the notification server, 0 never expires
+    pub timeout_ms: i32,
+    pub show_cover: bool
+}
+
+impl NotificationConfig {
+    pub fn defaults() -> NotificationConfig {
+        NotificationConfig {
+            enabled: false,
+            summary: "{title}".to_string(),
+            body: "{artist}\\n{album}".to_string(),
+            timeout_ms: -1,
+            show_cover: true
+        }
+    }
 }
 
 
 impl Config {
-    pub fn new() -> Config {
+    //reads the config file at config_path, a missing file is created with the default values
+    //an invalid config prints every problem and exits, profile replaces profile in [general]
+    //upgrade lets the service migrate an outdated file, other commands never change an existing file
+    pub fn load(config_path: String, profile: Option<&str>, upgrade: bool) -> Config {
+        if !Path::new(&config_path).exists() {
+            Config::write_defaults(&config_path);
+            return Config::defaults()
+        }
+        //older files are upgraded before they are read
+        if upgrade {
+            match config_migration::auto_migrate(&config_path) {
+                Ok(Some(migration)) => eprintln!("config: {}", migration.summary),
+                Ok(None) => (),
+                Err(error) => eprintln!("config: cannot migrate: {error}")
+            }
+        }
+        let (config, report) = Config::check(&config_path, profile);
+        for warning in &report.warnings {
+            eprintln!("config warning: {warning}");
+        }
+        if !report.errors.is_empty() {
+            for error in &report.errors {
+                eprintln!("config error: {error}");
+            }
+            eprintln!("{config_path} is invalid, see \"nowplaying_1lt config check\"");
+            process::exit(EXIT_ERROR);
+        }
+        config
+    }
+
+    //Reads and validates the config file, invalid values keep their default
+    //profile replaces profile in [general], Some("") uses no profile
+    pub fn check(config_path: &String, profile: Option<&str>) -> (Config, ConfigReport) {
+        let mut report = ConfigReport::default();
+        if !Path::new(config_path).exists() {
+            report.errors.push(ConfigIssue::file(&format!("{config_path} does not exist"), "create it with \"nowplaying_1lt config init\""));
+            return (Config::defaults(), report)
+        }
+        let mut file = match config_format::read(config_path) {
+            Ok(file) => file,
+            Err(err) => {
+                report.errors.push(ConfigIssue::file(&format!("cannot read {config_path}: {err}"), ""));
+                return (Config::defaults(), report)
+            }
+        };
+        let text = fs::read_to_string(config_path).unwrap_or_default();
+
+        let lines = LineIndex::new(&text);
+
+        let mut groups: Vec<String> = KNOWN_GROUPS.iter().map(|group| group.to_string()).collect();
+        groups.extend(file.config_groups.iter().map(|group| group.group_name()));
+        let mut overrides = Overrides::from_env(&groups, &mut report);
+        let profile = match profile {
+            Some(profile) => profile.to_string(),
+            None => overrides.value("general", "profile")
+                .or(file.config_groups.iter().find(|group| group.group_name() == "general").and_then(|group| group.get_config_attribute("profile".to_string()).ok()).map(|attribute| attribute.value))
+                .unwrap_or_default()
+        };
+        let profile = profile.trim().to_string();
+        if profile != "" {
+            match file.config_groups.iter().find(|group| group.group_name() == format!("profile.{profile}")) {
+                Some(profile_group) => overrides.add_profile(profile_group, &lines, &mut report),
+                None => report.errors.push(ConfigIssue {
+                    group: "general".to_string(),
+                    key: "profile".to_string(),
+                    location: lines.line("general", "profile"),
+                    message: format!("\"{profile}\" does not exist"),
+                    hint: format!("add a [profile.{profile}] group")
+                })
+            }
+        }
+        //environment variables and profiles may set keys of groups the file does not have
+        for group in overrides.groups() {
+            if !file.config_groups.iter().any(|file_group| file_group.group_name() == group) {
+                let _ = file.add_config_group(group);
+            }
+        }
+
+        let mut config = Config::read_config(file, &lines, &overrides, &mut report);
+        config.profile = profile;
+        //only the directory is replaced, a renamed now_playing.txt keeps its name
+        if let Some(data_directory) = paths::data_directory_override() {
+            let file_name = Path::new(&config.nowplaying_path).file_name().map(|name| name.to_os_string()).unwrap_or_else(|| "now_playing.txt".into());
+            config.nowplaying_path = data_directory.join(file_name).display().to_string();
+        }
+        (config, report)
+    }
+
+    //writes a config file with the default values, existing files are overwritten
+    pub fn write_defaults(config_path: &String) {
+        if let Some(directory) = Path::new(config_path).parent() {
+            if !directory.as_os_str().is_empty() && !directory.is_dir() {
+                match fs::create_dir_all(directory) {
+                    Ok(()) => (),
+                    Err(err) => panic!("Could not create config directorys: {:?}", err)
+                }
+            }
+        }
+        Config::write_default_config(config_path, &Config::defaults());
+    }
+
+    pub fn defaults() -> Config {
         let nowplaying_path = format!("{}now_playing.txt", get_default_work_directory());
+        let template = String::new();
         let display_artist = true;
         let display_album = true;
         let display_title = true;
         let sleep_duration = time::Duration::from_millis(1000);
+        let on_exit = "clear".to_string();
+        let offline_template = "offline".to_string();
         let port = "9500".to_string();
         let mut web_files: Vec<String> = Vec::new();
         web_files.push("/nowplaying.html".to_string());
         web_files.push("/nowplaying.css".to_string());
         web_files.push("/nowplaying.js".to_string());
+        let web_theme = String::new();
         let public = false;
+        let player_priority: Vec<String> = Vec::new();
+        let profile = String::new();
+        let profiles: Vec<String> = Vec::new();
+        let history_enabled = true;
+        let history_max_size_kb = 1024;
+        let history_max_files = 10;
+        let hooks = HookConfig::defaults();
+        let webhooks: Vec<WebhookConfig> = Vec::new();
+        let mqtt = MqttConfig::defaults();
+        let obs = ObsConfig::defaults();
+        let discord = DiscordConfig::defaults();
+        let irc = IrcConfig::defaults();
+        let lastfm = LastFmConfig::defaults();
+        let listenbrainz = ListenBrainzConfig::defaults();
+        let notifications = NotificationConfig::defaults();
+        let dbus_enabled = false;
+        let dbus_name = "dev.lt1.NowPlaying".to_string();
+        let socket_enabled = false;
+        let socket_path = String::new();
+        let log_level = "info".to_string();
+        let log_modules = String::new();
+        let log_timestamps = true;
+        let log_format = "auto".to_string();
+        let log_file = false;
+        let log_file_max_size_kb = 1024;
+        let log_file_max_files = 5;
 
         let default_config = Config {
             nowplaying_path,
+            template,
             display_artist,
             display_album,
             display_title,
             sleep_duration,
+            on_exit,
+            offline_template,
             port,
             web_files,
-            public
+            web_theme,
+            public,
+            player_priority,
+            profile,
+            profiles,
+            history_enabled,
+            history_max_size_kb,
+            history_max_files,
+            hooks,
+            webhooks,
+            mqtt,
+            obs,
+            discord,
+            irc,
+            lastfm,
+            listenbrainz,
+            notifications,
+            dbus_enabled,
+            dbus_name,
+            socket_enabled,
+            socket_path,
+            log_level,
+            log_modules,
+            log_timestamps,
+            log_format,
+            log_file,
+            log_file_max_size_kb,
+            log_file_max_files
         };
 
-        Config::read_config(Config::get_config_location(), default_config)
+        default_config
     }
 
-    fn get_config_location() -> String {
-        let home_path = match env::var("HOME") {
-            Ok(home_path) => home_path,
-            Err(err) => format!("No home directory set. Can't locate config file: {:?}", err)
-        };
-        let config_path = format!("{}/.config/1lt_software/1lt_nowplaying/", home_path);
+    //directory containing now_playing.txt and the play history
+    pub fn work_directory(&self) -> PathBuf {
+        match Path::new(&self.nowplaying_path).parent() {
+            Some(parent) => parent.to_path_buf(),
+            None => PathBuf::from(get_default_work_directory())
+        }
+    }
 
-        //Create folders if they don't exist
-        if !Path::new(&config_path).is_dir() {
-            match fs::create_dir_all(&config_path) {
-                Ok(()) => (),
-                Err(err) => panic!("Could not create config directorys: {:?}", err)
+    //$NOWPLAYING_CONFIG or the config file in the XDG config directory, --config is handled by the command line
+    //config.toml wins over config.json and the legacy config, new files are written as config.toml
+    pub fn get_config_location() -> String {
+        if let Ok(config_path) = env::var(paths::ENV_CONFIG) {
+            if config_path != "" {
+                return config_path
             }
-        };
-        
-        format!("{}config", config_path)
-    }   
+        }
+        let directory = paths::config_directory();
+        let config_path = ["config.toml", "config.json", "config"].iter()
+            .map(|name| directory.join(name))
+            .find(|config_path| config_path.exists())
+            .unwrap_or(directory.join("config.toml"));
+        config_path.display().to_string()
+    }
 
-    fn read_config(config_path: String, default_config: Config) -> Config {
-        //create configfile with default values if config file does not exist
-        if !Path::new(&config_path).exists() {
-            Config::write_default_config(&config_path, &default_config);
-            return default_config.clone()
-        }
-        else {
-            let file = match read_config_file(config_path.clone()) {
-                Ok(file) => file,
-                Err(err) => panic!("Error while reading config file: {}", err)
-            };
-
-            let mut config = default_config.clone();
-
-            for cfgg in file.config_groups {
-                if cfgg.group_name() == "general" {
-                    config.nowplaying_path = match cfgg.get_config_attribute(format!("nowplaying_path")) {
-                        Ok(att) => att.value,
-                        Err(()) => config.nowplaying_path
-                    };
-                    config.display_artist = match cfgg.get_config_attribute(format!("display_artist")) {
-                        Ok(att) => att.value.parse::<bool>().unwrap(),
-                        Err(()) => config.display_artist
-                    };
-                    config.display_album = match cfgg.get_config_attribute(format!("display_album")) {
-                        Ok(att) => att.value.parse::<bool>().unwrap(),
-                        Err(()) => config.display_album
+    //Reads all groups, an invalid value is reported and its default kept so every problem shows up at once
+    fn read_config(file: ConfigFile, lines: &LineIndex, overrides: &Overrides, report: &mut ConfigReport) -> Config {
+        let default_config = Config::defaults();
+        let mut config = default_config.clone();
+
+        for cfgg in file.config_groups {
+            //profiles are applied through the overrides
+            if let Some(profile) = cfgg.group_name().strip_prefix("profile.") {
+                config.profiles.push(profile.to_string());
+                continue
+            }
+            let mut group = GroupReader::new(&cfgg, lines, overrides, report);
+            if cfgg.group_name() == "general" {
+                let version = group.number("config_version", 1);
+                if version > CONFIG_VERSION {
+                    group.warning("config_version", &format!("version {version} is newer than this release supports ({CONFIG_VERSION})"), "settings added later are ignored");
+                } else if version < CONFIG_VERSION {
+                    group.warning("config_version", &format!("version {version} is older than the current layout ({CONFIG_VERSION})"), "run \"nowplaying_1lt config migrate\"");
+                }
+                config.nowplaying_path = group.string("nowplaying_path", config.nowplaying_path);
+                config.template = group.string("template", config.template);
+                config.display_artist = group.bool("display_artist", config.display_artist);
+                config.display_album = group.bool("display_album", config.display_album);
+                config.display_title = group.bool("display_title", config.display_title);
+                config.sleep_duration = time::Duration::from_millis(group.number("sleep_duration", config.sleep_duration.as_millis() as u64));
+                let port = group.number::<u16>("port", 9500);
+                if port == 80 || port >= 1024 {
+                    config.port = port.to_string();
+                } else {
+                    group.error("port", &format!("{port} is not within the accepted range"), "use 80 or a port between 1024 and 65535");
+                }
+                config.public = group.bool("public", config.public);
+                config.player_priority = group.string("player_priority", String::new()).split(',')
+                    .map(|player| player.trim().to_string())
+                    .filter(|player| player != "")
+                    .collect();
+                //read in check, it decides which profile is applied
+                group.string("profile", String::new());
+                config.on_exit = group.choice("on_exit", config.on_exit, &["clear", "offline", "keep"]);
+                config.offline_template = group.string("offline_template", config.offline_template);
+            }
+            else if cfgg.group_name() == "web_files" {
+                //the group may only be there for a profile or environment override of theme
+                let web_files = group.numbered("web_file");
+                if config.web_files == default_config.web_files && !web_files.is_empty() {
+                    config.web_files = Vec::new();
+                }
+                for (_name, web_file) in web_files {
+                    config.web_files.push(web_file)
+                }
+                config.web_theme = group.string("theme", config.web_theme);
+                if config.web_theme.contains('/') || config.web_theme.contains("..") {
+                    group.error("theme", &format!("\"{}\" is not a theme name", config.web_theme), "use the name of a directory in web_display/themes");
+                    config.web_theme = String::new();
+                }
+            }
+            else if cfgg.group_name() == "history" {
+                config.history_enabled = group.bool("enabled", config.history_enabled);
+                config.history_max_size_kb = group.number("max_size_kb", config.history_max_size_kb);
+                config.history_max_files = group.number("max_files", config.history_max_files);
+            }
+            else if cfgg.group_name() == "hooks" {
+                for (_name, command) in group.numbered("command") {
+                    config.hooks.commands.push(command)
+                }
+                config.hooks.on_pause = group.bool("on_pause", config.hooks.on_pause);
+                config.hooks.on_stop = group.bool("on_stop", config.hooks.on_stop);
+                config.hooks.timeout = time::Duration::from_millis(group.number("timeout_ms", config.hooks.timeout.as_millis() as u64));
+            }
+            //every group named webhook* is one webhook target
+            else if cfgg.group_name().starts_with("webhook") {
+                let mut webhook = WebhookConfig::new(group.string("url", String::new()));
+                if webhook.url == "" {
+                    group.error("url", "the webhook has no url", "add url = https://... or remove the group");
+                }
+                for (key, header) in group.numbered("header") {
+                    match header.split_once(':') {
+                        Some((name, value)) => webhook.headers.push((name.trim().to_string(), value.trim().to_string())),
+                        None => group.error(&key, &format!("\"{header}\" is not a header"), "use the form \"Name: value\"")
                     };
-                    config.display_title = match cfgg.get_config_attribute(format!("display_title")) {
-                        Ok(att) => att.value.parse::<bool>().unwrap(),
-                        Err(()) => config.display_title
+                }
+                webhook.body = group.string("body", webhook.body);
+                webhook.retries = group.number("retries", webhook.retries);
+                webhook.timeout = time::Duration::from_millis(group.number("timeout_ms", webhook.timeout.as_millis() as u64));
+                config.webhooks.push(webhook);
+            }
+            else if cfgg.group_name() == "mqtt" {
+                config.mqtt.enabled = group.bool("enabled", config.mqtt.enabled);
+                config.mqtt.host = group.string("host", config.mqtt.host);
+                config.mqtt.port = group.number("port", config.mqtt.port);
+                config.mqtt.client_id = group.string("client_id", config.mqtt.client_id);
+                config.mqtt.username = group.string("username", config.mqtt.username);
+                config.mqtt.password = group.string("password", config.mqtt.password);
+                config.mqtt.topic_prefix = group.string("topic_prefix", config.mqtt.topic_prefix);
+                config.mqtt.commands = group.bool("commands", config.mqtt.commands);
+                config.mqtt.keep_alive = time::Duration::from_secs(group.number("keep_alive_s", config.mqtt.keep_alive.as_secs()));
+            }
+            else if cfgg.group_name() == "obs" {
+                config.obs.enabled = group.bool("enabled", config.obs.enabled);
+                config.obs.url = group.string("url", config.obs.url);
+                config.obs.password = group.string("password", config.obs.password);
+                //text_source<N> names a text source, text_template<N> its template
+                let templates = group.numbered("text_template");
+                for (key, source) in group.numbered("text_source") {
+                    let index = key.trim_start_matches("text_source");
+                    let source_template = match templates.iter().find(|(name, _template)| *name == format!("text_template{index}")) {
+                        Some((_name, template)) => template.clone(),
+                        None => String::new()
                     };
-                    config.sleep_duration = match cfgg.get_config_attribute(format!("sleep_duration")) {
-                        Ok(att) => time::Duration::from_millis(att.value.parse::<u64>().unwrap()),
-                        Err(()) => config.sleep_duration
-                    };                    
-                }
-                else if cfgg.group_name() == "web_files" {
-                    if config.web_files == default_config.web_files {
-                        config.web_files = Vec::new();
-                    }
-                    for config_attribute in cfgg.config_attributes() {
-                        if config_attribute.name.contains("web_file") {
-                            config.web_files.push(config_attribute.value)
-                        }
+                    config.obs.text_sources.push((source, source_template));
+                }
+                config.obs.image_source = group.string("image_source", config.obs.image_source);
+                config.obs.visibility_scene = group.string("visibility_scene", config.obs.visibility_scene);
+                config.obs.visibility_source = group.string("visibility_source", config.obs.visibility_source);
+            }
+            else if cfgg.group_name() == "discord" {
+                config.discord.enabled = group.bool("enabled", config.discord.enabled);
+                config.discord.client_id = group.string("client_id", config.discord.client_id);
+                if config.discord.enabled && config.discord.client_id == "" {
+                    group.error("client_id", "discord is enabled but no client_id is set", "create an application in the discord developer portal and use its id");
+                }
+            }
+            else if cfgg.group_name() == "irc" {
+                config.irc.enabled = group.bool("enabled", config.irc.enabled);
+                config.irc.server = group.string("server", config.irc.server);
+                config.irc.port = group.number("port", config.irc.port);
+                config.irc.nick = group.string("nick", config.irc.nick).to_lowercase();
+                config.irc.password = group.string("password", config.irc.password);
+                //comma separated, the leading # is optional
+                let channels = group.string("channel", String::new());
+                if channels != "" {
+                    config.irc.channels = channels.split(',').map(|channel| channel.trim().to_lowercase()).filter(|channel| channel != "").collect();
+                }
+                config.irc.song_command = group.string("song_command", config.irc.song_command).to_lowercase();
+                config.irc.song_template = group.string("song_template", config.irc.song_template);
+                config.irc.last_song_command = group.string("last_song_command", config.irc.last_song_command).to_lowercase();
+                config.irc.last_song_template = group.string("last_song_template", config.irc.last_song_template);
+                config.irc.command_cooldown = time::Duration::from_secs(group.number("command_cooldown_s", config.irc.command_cooldown.as_secs()));
+                config.irc.announce = group.bool("announce", config.irc.announce);
+                config.irc.announce_template = group.string("announce_template", config.irc.announce_template);
+                config.irc.announce_interval = time::Duration::from_secs(group.number("announce_interval_s", config.irc.announce_interval.as_secs()));
+                if config.irc.enabled && config.irc.nick == "" {
+                    group.error("nick", "irc is enabled but no nick is set", "set nick to the account name of the bot");
+                }
+                if config.irc.enabled && config.irc.channels.is_empty() {
+                    group.error("channel", "irc is enabled but no channel is set", "set channel to one or more comma separated channels");
+                }
+            }
+            else if cfgg.group_name() == "lastfm" {
+                config.lastfm.enabled = group.bool("enabled", config.lastfm.enabled);
+                config.lastfm.base_url = group.string("base_url", config.lastfm.base_url);
+                config.lastfm.api_key = group.string("api_key", config.lastfm.api_key);
+                config.lastfm.api_secret = group.string("api_secret", config.lastfm.api_secret);
+                config.lastfm.session_key = group.string("session_key", config.lastfm.session_key);
+                config.lastfm.username = group.string("username", config.lastfm.username);
+                config.lastfm.password = group.string("password", config.lastfm.password);
+                if config.lastfm.enabled && (config.lastfm.api_key == "" || config.lastfm.api_secret == "") {
+                    group.error("api_key", "lastfm is enabled but api_key or api_secret is not set", "create an api account at last.fm/api");
+                }
+                if config.lastfm.enabled && config.lastfm.session_key == "" && config.lastfm.username == "" {
+                    group.error("session_key", "lastfm is enabled but neither session_key nor username/password is set", "set username and password, the session key is fetched on start");
+                }
+            }
+            else if cfgg.group_name() == "listenbrainz" {
+                config.listenbrainz.enabled = group.bool("enabled", config.listenbrainz.enabled);
+                config.listenbrainz.base_url = group.string("base_url", config.listenbrainz.base_url);
+                config.listenbrainz.token = group.string("token", config.listenbrainz.token);
+                if config.listenbrainz.enabled && config.listenbrainz.token == "" {
+                    group.error("token", "listenbrainz is enabled but no token is set", "copy the user token from your listenbrainz settings");
+                }
+            }
+            else if cfgg.group_name() == "notifications" {
+                config.notifications.enabled = group.bool("enabled", config.notifications.enabled);
+                config.notifications.summary = group.string("summary", config.notifications.summary);
+                config.notifications.body = group.string("body", config.notifications.body);
+                config.notifications.timeout_ms = group.number("timeout_ms", config.notifications.timeout_ms);
+                config.notifications.show_cover = group.bool("show_cover", config.notifications.show_cover);
+            }
+            else if cfgg.group_name() == "dbus" {
+                config.dbus_enabled = group.bool("enabled", config.dbus_enabled);
+                config.dbus_name = group.string("name", config.dbus_name);
+            }
+            else if cfgg.group_name() == "socket" {
+                config.socket_enabled = group.bool("enabled", config.socket_enabled);
+                config.socket_path = group.string("path", config.socket_path);
+            }
+            else if cfgg.group_name() == "logging" {
+                config.log_level = group.choice("level", config.log_level, &["off", "error", "warn", "info", "debug", "trace"]);
+                config.log_modules = group.string("modules", config.log_modules);
+                for module in config.log_modules.split(',').filter(|module| module.trim() != "") {
+                    match module.split_once('=').map(|(_name, level)| level.trim().parse::<log::LevelFilter>()) {
+                        Some(Ok(_level)) => (),
+                        _ => group.error("modules", &format!("\"{}\" is not a module filter", module.trim()), "use module=level pairs like web_display=debug,irc=warn")
                     }
                 }
+                config.log_timestamps = group.bool("timestamps", config.log_timestamps);
+                config.log_format = group.choice("format", config.log_format, &["auto", "text", "journald"]);
+                config.log_file = group.bool("file", config.log_file);
+                config.log_file_max_size_kb = group.number("file_max_size_kb", config.log_file_max_size_kb);
+                config.log_file_max_files = group.number("file_max_files", config.log_file_max_files);
             }
-            return config
+            else {
+                report.warnings.push(config_reader::unknown_group(&cfgg.group_name(), lines, KNOWN_GROUPS));
+                continue
+            }
+            group.finish();
         }
+        config
     }
 
-    #[allow(unused_must_use)]
     fn write_default_config(config_path: &String, default_config: &Config) {
+        match config_format::write(config_path, &Config::default_config_file(config_path, default_config)) {
+            //stderr, stdout may be read by a status bar
+            Ok(()) => eprintln!("No config file found! Created a new one at {} \n", config_path),
+            Err(err) => panic!("Could not write config file: {}", err)
+        }
+    }
+
+    //the layout written for new config files, migrations add the keys an older file is missing
+    #[allow(unused_must_use)]
+    pub fn default_config_file(config_path: &String, default_config: &Config) -> ConfigFile {
         let mut new_config: ConfigFile = ConfigFile::new(config_path.clone());
         
         new_config.add_config_group(format!("general"));
+        new_config.config_groups[0].add_config_attribute(ConfigAttribute::new("config_version".to_string(), CONFIG_VERSION.to_string()).unwrap());
         new_config.config_groups[0].add_config_attribute(ConfigAttribute::new(format!("nowplaying_path"), default_config.nowplaying_path.clone()).unwrap());
         new_config.config_groups[0].add_config_attribute(ConfigAttribute::new(format!("display_artist"), format!("{}", default_config.display_artist)).unwrap());
         new_config.config_groups[0].add_config_attribute(ConfigAttribute::new(format!("display_album"), format!("{}", default_config.display_album)).unwrap());
         new_config.config_groups[0].add_config_attribute(ConfigAttribute::new(format!("display_title"), format!("{}", default_config.display_title)).unwrap());
         new_config.config_groups[0].add_config_attribute(ConfigAttribute::new(format!("sleep_duration"), default_config.sleep_duration.as_millis().to_string()).unwrap());
+        new_config.config_groups[0].add_config_attribute(ConfigAttribute::new("on_exit".to_string(), default_config.on_exit.clone()).unwrap());
+        new_config.config_groups[0].add_config_attribute(ConfigAttribute::new("offline_template".to_string(), default_config.offline_template.clone()).unwrap());
         new_config.config_groups[0].add_config_attribute(ConfigAttribute::new("port".to_string(), default_config.port.clone()).unwrap());
         new_config.config_groups[0].add_config_attribute(ConfigAttribute::new("public".to_string(), default_config.public.to_string()).unwrap());
         new_config.add_config_group("web_files".to_string());
         new_config.config_groups[1].add_config_attribute(ConfigAttribute::new("web_file0".to_string(), default_config.web_files[0].clone()).unwrap());
         new_config.config_groups[1].add_config_attribute(ConfigAttribute::new("web_file1".to_string(), default_config.web_files[1].clone()).unwrap());
         new_config.config_groups[1].add_config_attribute(ConfigAttribute::new("web_file2".to_string(), default_config.web_files[2].clone()).unwrap());
-        match write_config_file(&new_config) {
-            Ok(()) => println!("No config file found! Created a new one at {} \n", config_path),
-            Err(err) => panic!("Could not write config file: {}", err)
-        }
+        new_config.add_config_group("history".to_string());
+        new_config.config_groups[2].add_config_attribute(ConfigAttribute::new("enabled".to_string(), default_config.history_enabled.to_string()).unwrap());
+        new_config.config_groups[2].add_config_attribute(ConfigAttribute::new("max_size_kb".to_string(), default_config.history_max_size_kb.to_string()).unwrap());
+        new_config.config_groups[2].add_config_attribute(ConfigAttribute::new("max_files".to_string(), default_config.history_max_files.to_string()).unwrap());
+        new_config.add_config_group("hooks".to_string());
+        new_config.config_groups[3].add_config_attribute(ConfigAttribute::new("on_pause".to_string(), default_config.hooks.on_pause.to_string()).unwrap());
+        new_config.config_groups[3].add_config_attribute(ConfigAttribute::new("on_stop".to_string(), default_config.hooks.on_stop.to_string()).unwrap());
+        new_config.config_groups[3].add_config_attribute(ConfigAttribute::new("timeout_ms".to_string(), default_config.hooks.timeout.as_millis().to_string()).unwrap());
+        new_config.add_config_group("mqtt".to_string());
+        new_config.config_groups[4].add_config_attribute(ConfigAttribute::new("enabled".to_string(), default_config.mqtt.enabled.to_string()).unwrap());
+        new_config.config_groups[4].add_config_attribute(ConfigAttribute::new("host".to_string(), default_config.mqtt.host.clone()).unwrap());
+        new_config.config_groups[4].add_config_attribute(ConfigAttribute::new("port".to_string(), default_config.mqtt.port.to_string()).unwrap());
+        new_config.config_groups[4].add_config_attribute(ConfigAttribute::new("client_id".to_string(), default_config.mqtt.client_id.clone()).unwrap());
+        new_config.config_groups[4].add_config_attribute(ConfigAttribute::new("topic_prefix".to_string(), default_config.mqtt.topic_prefix.clone()).unwrap());
+        new_config.config_groups[4].add_config_attribute(ConfigAttribute::new("commands".to_string(), default_config.mqtt.commands.to_string()).unwrap());
+        new_config.config_groups[4].add_config_attribute(ConfigAttribute::new("keep_alive_s".to_string(), default_config.mqtt.keep_alive.as_secs().to_string()).unwrap());
+        new_config.add_config_group("obs".to_string());
+        new_config.config_groups[5].add_config_attribute(ConfigAttribute::new("enabled".to_string(), default_config.obs.enabled.to_string()).unwrap());
+        new_config.config_groups[5].add_config_attribute(ConfigAttribute::new("url".to_string(), default_config.obs.url.clone()).unwrap());
+        new_config.add_config_group("discord".to_string());
+        new_config.config_groups[6].add_config_attribute(ConfigAttribute::new("enabled".to_string(), default_config.discord.enabled.to_string()).unwrap());
+        new_config.add_config_group("irc".to_string());
+        new_config.config_groups[7].add_config_attribute(ConfigAttribute::new("enabled".to_string(), default_config.irc.enabled.to_string()).unwrap());
+        new_config.config_groups[7].add_config_attribute(ConfigAttribute::new("server".to_string(), default_config.irc.server.clone()).unwrap());
+        new_config.config_groups[7].add_config_attribute(ConfigAttribute::new("port".to_string(), default_config.irc.port.to_string()).unwrap());
+        new_config.add_config_group("lastfm".to_string());
+        new_config.config_groups[8].add_config_attribute(ConfigAttribute::new("enabled".to_string(), default_config.lastfm.enabled.to_string()).unwrap());
+        new_config.config_groups[8].add_config_attribute(ConfigAttribute::new("base_url".to_string(), default_config.lastfm.base_url.clone()).unwrap());
+        new_config.add_config_group("listenbrainz".to_string());
+        new_config.config_groups[9].add_config_attribute(ConfigAttribute::new("enabled".to_string(), default_config.listenbrainz.enabled.to_string()).unwrap());
+        new_config.config_groups[9].add_config_attribute(ConfigAttribute::new("base_url".to_string(), default_config.listenbrainz.base_url.clone()).unwrap());
+        new_config.add_config_group("notifications".to_string());
+        new_config.config_groups[10].add_config_attribute(ConfigAttribute::new("enabled".to_string(), default_config.notifications.enabled.to_string()).unwrap());
+        new_config.config_groups[10].add_config_attribute(ConfigAttribute::new("summary".to_string(), default_config.notifications.summary.clone()).unwrap());
+        new_config.config_groups[10].add_config_attribute(ConfigAttribute::new("body".to_string(), default_config.notifications.body.clone()).unwrap());
+        new_config.add_config_group("dbus".to_string());
+        new_config.config_groups[11].add_config_attribute(ConfigAttribute::new("enabled".to_string(), default_config.dbus_enabled.to_string()).unwrap());
+        new_config.config_groups[11].add_config_attribute(ConfigAttribute::new("name".to_string(), default_config.dbus_name.clone()).unwrap());
+        new_config.add_config_group("socket".to_string());
+        new_config.config_groups[12].add_config_attribute(ConfigAttribute::new("enabled".to_string(), default_config.socket_enabled.to_string()).unwrap());
+        new_config.add_config_group("logging".to_string());
+        new_config.config_groups[13].add_config_attribute(ConfigAttribute::new("level".to_string(), default_config.log_level.clone()).unwrap());
+        new_config.config_groups[13].add_config_attribute(ConfigAttribute::new("timestamps".to_string(), default_config.log_timestamps.to_string()).unwrap());
+        new_config.config_groups[13].add_config_attribute(ConfigAttribute::new("format".to_string(), default_config.log_format.clone()).unwrap());
+        new_config.config_groups[13].add_config_attribute(ConfigAttribute::new("file".to_string(), default_config.log_file.to_string()).unwrap());
+        new_config
     }
 }
 
 
 pub fn get_default_work_directory() -> String {
-    let home_path = match env::var("HOME") {
-        Ok(home_path) => home_path,
-        Err(err) => panic!("No home directory set. Can't crate now_playing.txt: {:?}", err),
-    };
-    format!("{}/.local/share/1lt_software/1lt_nowplaying/", home_path)
-}
\ No newline at end of file
+    format!("{}/", paths::default_data_directory().display())
+}
@@ -0,0 +1,395 @@
+use std::fs;
+use std::path::Path;
+
+use config_1lt::data::{config_attribute::ConfigAttribute, config_file::ConfigFile};
+use config_1lt::file::{read::read_config_file, write::write_config_file};
+use toml::{Table, Value};
+
+//keys that are numbered in the legacy format (web_file0, web_file1, ...) and arrays in TOML and JSON
+const NUMBERED_KEYS: &[&str] = &["web_file", "command", "header", "text_source", "text_template"];
+
+#[derive(Clone, Copy, PartialEq)]
+pub enum Format {
+    //the config_1lt format: [group] and key=value lines
+    Legacy,
+    Toml,
+    Json
+}
+
+impl Format {
+    //by file extension, everything that is not .toml or .json is the legacy format
+    pub fn of(path: &str) -> Format {
+        match Path::new(path).extension().and_then(|extension| extension.to_str()) {
+            Some("toml") => Format::Toml,
+            Some("json") => Format::Json,
+            _ => Format::Legacy
+        }
+    }
+
+    pub fn from_name(name: &str) -> Option<Format> {
+        match name {
+            "legacy" => Some(Format::Legacy),
+            "toml" => Some(Format::Toml),
+            "json" => Some(Format::Json),
+            _ => None
+        }
+    }
+
+    pub fn name(&self) -> &'static str {
+        match self {
+            Format::Legacy => "legacy",
+            Format::Toml => "toml",
+            Format::Json => "json"
+        }
+    }
+}
+
+//Reads a config file in the format of its extension into groups and string values like the legacy format
+pub fn read(config_path: &String) -> Result<ConfigFile, String> {
+    let format = Format::of(config_path);
+    if format == Format::Legacy {
+        return read_config_file(config_path.clone()).map_err(|error| error.to_string())
+    }
+    let text = match fs::read_to_string(config_path) {
+        Ok(text) => text,
+        Err(error) => return Err(error.to_string())
+    };
+    let table = match format {
+        Format::Json => serde_json::from_str::<Table>(&text).map_err(|error| error.to_string())?,
+        //the TOML error ends with a newline after the marked line
+        _ => toml::from_str::<Table>(&text).map_err(|error| error.to_string().trim_end().to_string())?
+    };
+    from_table(config_path, &table)
+}
+
+//Writes the config file in the format of config_path, a legacy file is written to the path it was created with
+pub fn write(config_path: &String, file: &ConfigFile) -> Result<(), String> {
+    match Format::of(config_path) {
+        Format::Legacy => write_config_file(file).map_err(|error| error.to_string()),
+        format => fs::write(config_path, to_text(file, format)?).map_err(|error| error.to_string())
+    }
+}
+
+//The config file as TOML or JSON text, not available for the legacy format
+pub fn to_text(file: &ConfigFile, format: Format) -> Result<String, String> {
+    let table = to_table(file);
+    match format {
+        Format::Toml => toml::to_string_pretty(&table).map_err(|error| error.to_string()),
+        Format::Json => serde_json::to_string_pretty(&table).map(|json| format!("{json}\n")).map_err(|error| error.to_string()),
+        Format::Legacy => Err("the legacy format is written by config_1lt".to_string())
+    }
+}
+
+//group and key prefix of a table header in the legacy layout, [profile.chill.general] holds general.<key> of [profile.chill]
+pub fn legacy_group(header: &str) -> (String, String) {
+    let parts: Vec<&str> = header.split('.').map(|part| part.trim()).collect();
+    match parts[..] {
+        ["profile", profile, group] => (format!("profile.{profile}"), format!("{group}.")),
+        ["webhook", name] if name.chars().all(|c| c.is_ascii_digit()) => (format!("webhook{name}"), String::new()),
+        ["webhook", name] => (format!("webhook_{name}"), String::new()),
+        _ => (header.to_string(), String::new())
+    }
+}
+
+//a value as it is written in TOML, true/false and whole numbers without quotes
+pub fn toml_value(value: &str) -> Value {
+    match value {
+        "true" => Value::Boolean(true),
+        "false" => Value::Boolean(false),
+        //"0123" stays a string, the leading zero would get lost
+        _ => match value.parse::<i64>() {
+            Ok(number) if number.to_string() == value => Value::Integer(number),
+            _ => Value::String(value.to_string())
+        }
+    }
+}
+
+//Tables become groups, with these additions to the legacy layout:
+//[profile.<name>] may nest the groups it overrides ([profile.chill.general] template = ...),
+//[webhook.<name>] is the group webhook_<name> (webhook<N> for numbers) and [[webhook]] are webhook0, webhook1, ...
+#[allow(unused_must_use)]
+fn from_table(config_path: &String, table: &Table) -> Result<ConfigFile, String> {
+    let mut groups: Vec<(String, Vec<(String, String)>)> = Vec::new();
+    for (name, value) in table {
+        match (name.as_str(), value) {
+            ("profile", Value::Table(profiles)) => for (profile, value) in profiles {
+                let mut attributes = Vec::new();
+                match value {
+                    Value::Table(profile_table) => for (key, value) in profile_table {
+                        match value {
+                            Value::Table(group) => for (group_key, value) in attributes_of(group, &format!("profile.{profile}.{key}"))? {
+                                attributes.push((format!("{key}.{group_key}"), value));
+                            },
+                            value => add_attribute(&mut attributes, key, value, &format!("profile.{profile}"))?
+                        }
+                    },
+                    _ => return Err(format!("profile.{profile} must be a table"))
+                }
+                groups.push((format!("profile.{profile}"), attributes));
+            },
+            ("webhook", Value::Table(webhooks)) => {
+                let mut attributes = Vec::new();
+                for (key, value) in webhooks {
+                    match value {
+                        Value::Table(webhook) if key.chars().all(|c| c.is_ascii_digit()) => groups.push((format!("webhook{key}"), attributes_of(webhook, &format!("webhook.{key}"))?)),
+                        Value::Table(webhook) => groups.push((format!("webhook_{key}"), attributes_of(webhook, &format!("webhook.{key}"))?)),
+                        value => add_attribute(&mut attributes, key, value, "webhook")?
+                    }
+                }
+                if !attributes.is_empty() {
+                    groups.push(("webhook".to_string(), attributes));
+                }
+            }
+            ("webhook", Value::Array(webhooks)) => for (index, webhook) in webhooks.iter().enumerate() {
+                match webhook {
+                    Value::Table(webhook) => groups.push((format!("webhook{index}"), attributes_of(webhook, "webhook")?)),
+                    _ => return Err("[[webhook]] entries must be tables".to_string())
+                }
+            },
+            (name, Value::Table(group)) => groups.push((name.to_string(), attributes_of(group, name)?)),
+            (name, _value) => return Err(format!("{name} is not in a table, every key belongs to a group like [general]"))
+        }
+    }
+
+    let mut file = ConfigFile::new(config_path.clone());
+    for (index, (name, attributes)) in groups.into_iter().enumerate() {
+        file.add_config_group(name.clone());
+        for (key, value) in attributes {
+            match ConfigAttribute::new(key.clone(), value) {
+                Ok(attribute) => file.config_groups[index].add_config_attribute(attribute),
+                Err(_error) => return Err(format!("[{name}] {key} cannot be stored"))
+            };
+        }
+    }
+    Ok(file)
+}
+
+fn attributes_of(table: &Table, group: &str) -> Result<Vec<(String, String)>, String> {
+    let mut attributes = Vec::new();
+    for (key, value) in table {
+        add_attribute(&mut attributes, key, value, group)?;
+    }
+    Ok(attributes)
+}
+
+//arrays of numbered keys become key0, key1, ..., other arrays a comma separated list (channel, player_priority)
+//headers = { Authorization = "..." } are header0, header1, ... and [[obs.text_sources]] are text_source<N> with text_template<N>
+fn add_attribute(attributes: &mut Vec<(String, String)>, key: &str, value: &Value, group: &str) -> Result<(), String> {
+    match value {
+        Value::Array(values) if key == "text_sources" => for (index, source) in values.iter().enumerate() {
+            let source = match source {
+                Value::Table(source) => source,
+                _ => return Err(format!("[{group}] text_sources entries must be tables with source and template"))
+            };
+            for (source_key, value) in source {
+                match source_key.as_str() {
+                    "source" => attributes.push((format!("text_source{index}"), scalar(value, group, key)?)),
+                    "template" => attributes.push((format!("text_template{index}"), scalar(value, group, key)?)),
+                    _ => return Err(format!("[{group}] text_sources entries only have source and template, not {source_key}"))
+                }
+            }
+        },
+        Value::Array(values) if NUMBERED_KEYS.contains(&key) => for (index, value) in values.iter().enumerate() {
+            attributes.push((format!("{key}{index}"), scalar(value, group, key)?));
+        },
+        Value::Array(values) => {
+            let values: Result<Vec<String>, String> = values.iter().map(|value| scalar(value, group, key)).collect();
+            attributes.push((key.to_string(), values?.join(",")));
+        }
+        Value::Table(headers) if key == "headers" => for (index, (name, value)) in headers.iter().enumerate() {
+            attributes.push((format!("header{index}"), format!("{name}: {}", scalar(value, group, key)?)));
+        },
+        Value::Table(_table) => return Err(format!("[{group}] {key} cannot be a table")),
+        value => attributes.push((key.to_string(), scalar(value, group, key)?))
+    }
+    Ok(())
+}
+
+fn scalar(value: &Value, group: &str, key: &str) -> Result<String, String> {
+    match value {
+        Value::String(value) => Ok(value.clone()),
+        Value::Integer(value) => Ok(value.to_string()),
+        Value::Float(value) => Ok(value.to_string()),
+        Value::Boolean(value) => Ok(value.to_string()),
+        Value::Datetime(value) => Ok(value.to_string()),
+        _ => Err(format!("[{group}] {key} must be a string, number or boolean"))
+    }
+}
+
+//the legacy layout as tables, numbered keys as arrays and profiles nested by group
+fn to_table(file: &ConfigFile) -> Table {
+    let mut table = Table::new();
+    for group in &file.config_groups {
+        let attributes: Vec<(String, String)> = group.config_attributes().into_iter().map(|attribute| (attribute.name, attribute.value)).collect();
+        match group.group_name().strip_prefix("profile.") {
+            Some(profile) => {
+                let mut profile_table = Table::new();
+                let mut group_names: Vec<&str> = Vec::new();
+                for (key, _value) in &attributes {
+                    if let Some((group, _key)) = key.split_once('.') {
+                        if !group_names.contains(&group) {
+                            group_names.push(group);
+                        }
+                    }
+                }
+                //a key without a group is kept, config check reports it
+                let ungrouped: Vec<(String, String)> = attributes.iter().filter(|(key, _value)| !key.contains('.')).cloned().collect();
+                profile_table.extend(group_table(&ungrouped));
+                for group_name in group_names {
+                    let group_attributes: Vec<(String, String)> = attributes.iter()
+                        .filter_map(|(key, value)| key.split_once('.').filter(|(group, _key)| *group == group_name).map(|(_group, key)| (key.to_string(), value.clone())))
+                        .collect();
+                    profile_table.insert(group_name.to_string(), Value::Table(group_table(&group_attributes)));
+                }
+                if let Value::Table(profiles) = table.entry("profile").or_insert_with(|| Value::Table(Table::new())) {
+                    profiles.insert(profile.to_string(), Value::Table(profile_table));
+                }
+            }
+            None => { table.insert(group.group_name(), Value::Table(group_table(&attributes))); }
+        }
+    }
+    table
+}
+
+fn group_table(attributes: &[(String, String)]) -> Table {
+    let mut table = Table::new();
+    let mut text_sources: Vec<(String, Table)> = Vec::new();
+    for (key, value) in attributes {
+        let prefix = key.trim_end_matches(|c: char| c.is_ascii_digit());
+        let index = &key[prefix.len()..];
+        match prefix {
+            "text_source" | "text_template" if index != "" => {
+                let position = match text_sources.iter().position(|(source_index, _source)| source_index == index) {
+                    Some(position) => position,
+                    None => {
+                        text_sources.push((index.to_string(), Table::new()));
+                        text_sources.len() - 1
+                    }
+                };
+                let name = if prefix == "text_source" { "source" } else { "template" };
+                text_sources[position].1.insert(name.to_string(), Value::String(value.clone()));
+            }
+            prefix if index != "" && NUMBERED_KEYS.contains(&prefix) => {
+                if let Value::Array(values) = table.entry(prefix).or_insert_with(|| Value::Array(Vec::new())) {
+                    values.push(Value::String(value.clone()));
+                }
+            }
+            _ => { table.insert(key.clone(), toml_value(value)); }
+        }
+    }
+    if !text_sources.is_empty() {
+        table.insert("text_sources".to_string(), Value::Array(text_sources.into_iter().map(|(_index, source)| Value::Table(source)).collect()));
+    }
+    table
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    //groups and their keys sorted, TOML and JSON keep the values but group the profiles and put text_sources last
+    fn layout(file: &ConfigFile) -> Vec<(String, Vec<(String, String)>)> {
+        let mut layout: Vec<(String, Vec<(String, String)>)> = file.config_groups.iter()
+            .map(|group| {
+                let mut attributes: Vec<(String, String)> = group.config_attributes().into_iter().map(|attribute| (attribute.name, attribute.value)).collect();
+                attributes.sort();
+                (group.group_name(), attributes)
+            })
+            .collect();
+        layout.sort();
+        layout
+    }
+
+    fn legacy_file(groups: &[(&str, &[(&str, &str)])]) -> ConfigFile {
+        let mut file = ConfigFile::new("config".to_string());
+        for (index, (group, attributes)) in groups.iter().enumerate() {
+            let _ = file.add_config_group(group.to_string());
+            for (key, value) in attributes.iter() {
+                let _ = file.config_groups[index].add_config_attribute(ConfigAttribute::new(key.to_string(), value.to_string()).unwrap());
+            }
+        }
+        file
+    }
+
+    fn round_trip(file: &ConfigFile, format: Format) -> ConfigFile {
+        let text = to_text(file, format).unwrap();
+        let table = match format {
+            Format::Json => serde_json::from_str::<Table>(&text).unwrap(),
+            _ => toml::from_str::<Table>(&text).unwrap()
+        };
+        from_table(&"config".to_string(), &table).unwrap()
+    }
+
+    fn assert_round_trip(groups: &[(&str, &[(&str, &str)])]) {
+        let file = legacy_file(groups);
+        for format in [Format::Toml, Format::Json] {
+            assert!(layout(&round_trip(&file, format)) == layout(&file), "{} changed the config:\n{}", format.name(), to_text(&file, format).unwrap());
+        }
+    }
+
+    #[test]
+    fn profiles_round_trip() {
+        assert_round_trip(&[
+            ("general", &[("port", "8080"), ("template", "{artist} - {title}"), ("profile", "chill")]),
+            ("profile.chill", &[("general.template", "chill {title}"), ("general.display_album", "false"), ("obs.enabled", "true")]),
+            ("profile.loud", &[("discord.enabled", "false")])
+        ]);
+    }
+
+    #[test]
+    fn webhooks_and_headers_round_trip() {
+        assert_round_trip(&[
+            ("webhook0", &[("url", "http://localhost/a"), ("header0", "Authorization: Bearer x"), ("header1", "X-Source: nowplaying")]),
+            ("webhook_discord", &[("url", "http://localhost/b"), ("body", "{json}"), ("retries", "3")])
+        ]);
+    }
+
+    #[test]
+    fn text_sources_round_trip() {
+        assert_round_trip(&[
+            ("obs", &[("enabled", "true"), ("text_source0", "Title"), ("text_template0", "{title}"), ("text_source1", "Artist"), ("text_template1", "{artist}")]),
+            ("web_files", &[("web_file0", "/index.html"), ("web_file1", "/style.css")])
+        ]);
+    }
+
+    #[test]
+    fn values_keep_their_text() {
+        assert_round_trip(&[
+            ("general", &[("port", "0123"), ("public", "false"), ("sleep_duration", "1000"), ("offline_template", "")])
+        ]);
+    }
+
+    #[test]
+    fn nested_toml_becomes_the_legacy_layout() {
+        let toml = r#"
+[profile.chill.general]
+template = "chill"
+
+[webhook.discord]
+url = "http://localhost/b"
+headers = { Authorization = "Bearer x", X-Source = "nowplaying" }
+
+[webhook.2]
+url = "http://localhost/c"
+
+[[obs.text_sources]]
+source = "Title"
+template = "{title}"
+"#;
+        let file = from_table(&"config.toml".to_string(), &toml::from_str::<Table>(toml).unwrap()).unwrap();
+        assert!(layout(&file) == layout(&legacy_file(&[
+            ("profile.chill", &[("general.template", "chill")]),
+            ("webhook_discord", &[("url", "http://localhost/b"), ("header0", "Authorization: Bearer x"), ("header1", "X-Source: nowplaying")]),
+            ("webhook2", &[("url", "http://localhost/c")]),
+            ("obs", &[("text_source0", "Title"), ("text_template0", "{title}")])
+        ])));
+
+        //[[webhook]] cannot be mixed with [webhook.<name>] in one file
+        let toml = "[[webhook]]\nurl = \"http://localhost/a\"\n\n[[webhook]]\nurl = \"http://localhost/b\"\n";
+        let file = from_table(&"config.toml".to_string(), &toml::from_str::<Table>(toml).unwrap()).unwrap();
+        assert!(layout(&file) == layout(&legacy_file(&[
+            ("webhook0", &[("url", "http://localhost/a")]),
+            ("webhook1", &[("url", "http://localhost/b")])
+        ])));
+    }
+}
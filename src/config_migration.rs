@@ -0,0 +1,275 @@
+use std::fs;
+use std::path::Path;
+
+use config_1lt::data::{config_attribute::ConfigAttribute, config_file::ConfigFile};
+
+use crate::config::Config;
+use crate::config_format::{self, Format};
+
+//version of the config layout, written as config_version in [general]
+pub const CONFIG_VERSION: u32 = 2;
+
+//groups with their (key, value) pairs in file order, easier to change than a ConfigFile
+type Layout = Vec<(String, Vec<(String, String)>)>;
+
+//MIGRATIONS[n] upgrades version n + 1 to n + 2, files without config_version are version 1
+const MIGRATIONS: &[fn(&mut Layout, &mut Vec<String>)] = &[
+    migrate_1_to_2
+];
+
+const _: () = assert!(MIGRATIONS.len() == CONFIG_VERSION as usize - 1);
+
+//shown above the key by config new-keys
+const DESCRIPTIONS: &[(&str, &str, &str)] = &[
+    ("general", "config_version", "layout version of this file, do not change"),
+    ("general", "nowplaying_path", "file with the current track"),
+    ("general", "display_artist", "show the artist (true/false)"),
+    ("general", "display_album", "show the album (true/false)"),
+    ("general", "display_title", "show the title (true/false)"),
+    ("general", "sleep_duration", "milliseconds between two checks of the player"),
+    ("general", "on_exit", "now_playing.txt after SIGINT/SIGTERM: clear, offline or keep"),
+    ("general", "offline_template", "text for on_exit = offline, {previous} is the last track"),
+    ("general", "port", "port of the web display, 80 or 1024-65535"),
+    ("general", "public", "listen on all interfaces instead of only this machine"),
+    ("web_files", "web_file", "file served by the web display, relative to the web_display directory"),
+    ("history", "enabled", "record played tracks"),
+    ("history", "max_size_kb", "rotate the history file at this size"),
+    ("history", "max_files", "number of rotated history files to keep"),
+    ("hooks", "on_pause", "also run the commands when playback pauses"),
+    ("hooks", "on_stop", "also run the commands when playback stops"),
+    ("hooks", "timeout_ms", "kill a command running longer than this"),
+    ("mqtt", "enabled", "publish the state to an MQTT broker"),
+    ("mqtt", "host", "address of the broker"),
+    ("mqtt", "port", "port of the broker"),
+    ("mqtt", "client_id", "client id used to connect"),
+    ("mqtt", "topic_prefix", "topics are <topic_prefix>/state, <topic_prefix>/command, ..."),
+    ("mqtt", "commands", "control the player with messages on <topic_prefix>/command"),
+    ("mqtt", "keep_alive_s", "keep alive interval in seconds"),
+    ("obs", "enabled", "update OBS through obs-websocket v5"),
+    ("obs", "url", "address of obs-websocket"),
+    ("discord", "enabled", "show the track as Discord Rich Presence, needs client_id"),
+    ("irc", "enabled", "chat bot for IRC/Twitch, needs nick, password and channel"),
+    ("irc", "server", "IRC server"),
+    ("irc", "port", "IRC port"),
+    ("lastfm", "enabled", "scrobble to Last.fm, needs api_key, api_secret and a session_key or username/password"),
+    ("lastfm", "base_url", "Last.fm API address"),
+    ("listenbrainz", "enabled", "submit listens to ListenBrainz, needs token"),
+    ("listenbrainz", "base_url", "ListenBrainz API address"),
+    ("notifications", "enabled", "desktop notification on every track change"),
+    ("notifications", "summary", "notification title template"),
+    ("notifications", "body", "notification text template, \\n starts a new line"),
+    ("dbus", "enabled", "register a D-Bus service on the session bus"),
+    ("dbus", "name", "bus name of the service"),
+    ("socket", "enabled", "listen on a control socket"),
+    ("logging", "level", "error, warn, info, debug or trace"),
+    ("logging", "timestamps", "prefix log lines with the time"),
+    ("logging", "format", "auto, text or journald"),
+    ("logging", "file", "also write nowplaying.log into the work directory")
+];
+
+//version 2 introduced config_version, no key was renamed or removed
+fn migrate_1_to_2(_layout: &mut Layout, _notes: &mut Vec<String>) {}
+
+//What a migration changed
+pub struct Migration {
+    pub summary: String,
+    pub changes: Vec<String>
+}
+
+//Upgrades the config file in place and adds the default keys it is missing, the original is kept as a backup.
+//With only_outdated files already at CONFIG_VERSION are left alone.
+//Returns None if the file did not need a change.
+pub fn migrate(config_path: &String, only_outdated: bool) -> Result<Option<Migration>, String> {
+    let mut layout = read_layout(config_path)?;
+    let version = file_version(&layout);
+    if version >= CONFIG_VERSION && only_outdated {
+        return Ok(None)
+    }
+
+    let mut notes = Vec::new();
+    if version < CONFIG_VERSION {
+        for migration in &MIGRATIONS[version as usize - 1..] {
+            migration(&mut layout, &mut notes);
+        }
+        set_version(&mut layout);
+    }
+    for (group, key, value) in missing_keys(&layout, config_path) {
+        notes.push(format!("added [{group}] {key} = {value}"));
+        add_key(&mut layout, &group, key, value);
+    }
+    if notes.is_empty() && version >= CONFIG_VERSION {
+        return Ok(None)
+    }
+
+    let backup = backup_path(config_path, version);
+    if let Err(error) = fs::copy(config_path, &backup) {
+        return Err(format!("cannot back up {config_path} to {backup}: {error}"))
+    }
+    if let Err(error) = config_format::write(config_path, &to_config_file(&layout, config_path)) {
+        return Err(format!("cannot write {config_path}: {error}"))
+    }
+    let summary = match version < CONFIG_VERSION {
+        true => format!("migrated {config_path} from version {version} to {CONFIG_VERSION} ({} changes), the old file is {backup}", notes.len()),
+        false => format!("updated {config_path} ({} changes), the old file is {backup}", notes.len())
+    };
+    Ok(Some(Migration { summary, changes: notes }))
+}
+
+//Upgrades an outdated legacy config file when a migration step changes its keys, used when the service starts.
+//Files where only config_version would change are left alone, config check tells to run config migrate.
+//TOML and JSON files are never rewritten on their own, their comments and layout would get lost.
+pub fn auto_migrate(config_path: &String) -> Result<Option<Migration>, String> {
+    if Format::of(config_path) != Format::Legacy {
+        return Ok(None)
+    }
+    let layout = read_layout(config_path)?;
+    let version = file_version(&layout);
+    if version >= CONFIG_VERSION {
+        return Ok(None)
+    }
+    let mut migrated = layout.clone();
+    let mut notes = Vec::new();
+    for migration in &MIGRATIONS[version as usize - 1..] {
+        migration(&mut migrated, &mut notes);
+    }
+    match migrated == layout {
+        true => Ok(None),
+        false => migrate(config_path, true)
+    }
+}
+
+//The default keys missing in the config file, in config file syntax with a comment for each key
+pub fn new_keys(config_path: &String) -> Result<String, String> {
+    let layout = read_layout(config_path)?;
+    let missing = missing_keys(&layout, config_path);
+    let format = Format::of(config_path);
+    if missing.is_empty() {
+        return Ok(String::new())
+    }
+    //JSON has no comments, the keys are printed as an object to merge into the file
+    if format == Format::Json {
+        let mut missing_layout = Layout::new();
+        for (group, key, value) in missing {
+            add_key(&mut missing_layout, &group, key, value);
+        }
+        return config_format::to_text(&to_config_file(&missing_layout, config_path), Format::Json)
+    }
+    let mut text = String::new();
+    let mut current_group = String::new();
+    for (group, key, value) in missing {
+        if group != current_group {
+            text.push_str(&format!("{}[{group}]\n", if text == "" { "" } else { "\n" }));
+            current_group = group.clone();
+        }
+        if let Some(description) = description(&group, &key) {
+            text.push_str(&format!("# {description}\n"));
+        }
+        let value = match format {
+            Format::Toml => config_format::toml_value(&value).to_string(),
+            _ => value
+        };
+        text.push_str(&format!("{key} = {value}\n"));
+    }
+    Ok(text)
+}
+
+//version in [general], files written before config_version existed are version 1
+fn file_version(layout: &Layout) -> u32 {
+    let version = layout.iter()
+        .filter(|(group, _attributes)| group == "general")
+        .flat_map(|(_group, attributes)| attributes.iter())
+        .find(|(key, _value)| key == "config_version")
+        .map(|(_key, value)| value.trim().parse::<u32>());
+    match version {
+        None => 1,
+        Some(Ok(version)) => version.max(1),
+        //an invalid version is reported by config check, do not touch the file
+        Some(Err(_error)) => CONFIG_VERSION
+    }
+}
+
+fn set_version(layout: &mut Layout) {
+    let version = ("config_version".to_string(), CONFIG_VERSION.to_string());
+    match layout.iter_mut().find(|(group, _attributes)| group == "general") {
+        Some((_group, attributes)) => match attributes.iter_mut().find(|(key, _value)| key == "config_version") {
+            Some(attribute) => *attribute = version,
+            None => attributes.insert(0, version)
+        },
+        None => layout.insert(0, ("general".to_string(), vec![version]))
+    }
+}
+
+//keys of the default layout the file does not have, config_version is set by the migration itself
+//web_file<N> are only added together with a missing [web_files], they replace the default list
+fn missing_keys(layout: &Layout, config_path: &String) -> Vec<(String, String, String)> {
+    let defaults = to_layout(Config::default_config_file(config_path, &Config::defaults()));
+    let mut missing = Vec::new();
+    for (group, attributes) in defaults {
+        let file_attributes = layout.iter().find(|(file_group, _attributes)| *file_group == group).map(|(_group, attributes)| attributes);
+        for (key, value) in attributes {
+            let present = match file_attributes {
+                Some(_attributes) if key == "config_version" || group == "web_files" => true,
+                Some(attributes) => attributes.iter().any(|(file_key, _value)| *file_key == key),
+                None => key == "config_version"
+            };
+            if !present {
+                missing.push((group.clone(), key, value));
+            }
+        }
+    }
+    missing
+}
+
+fn add_key(layout: &mut Layout, group: &str, key: String, value: String) {
+    match layout.iter_mut().find(|(file_group, _attributes)| file_group == group) {
+        Some((_group, attributes)) => attributes.push((key, value)),
+        None => layout.push((group.to_string(), vec![(key, value)]))
+    }
+}
+
+fn description(group: &str, key: &str) -> Option<&'static str> {
+    //numbered keys like web_file0 share one description
+    let key = key.trim_end_matches(|c: char| c.is_ascii_digit());
+    DESCRIPTIONS.iter()
+        .find(|(description_group, description_key, _description)| *description_group == group && *description_key == key)
+        .map(|(_group, _key, description)| *description)
+}
+
+//config.v1.bak, config.v1.bak.1, ... an older backup is never overwritten
+fn backup_path(config_path: &String, version: u32) -> String {
+    let backup = format!("{config_path}.v{version}.bak");
+    let mut path = backup.clone();
+    let mut index = 1;
+    while Path::new(&path).exists() {
+        path = format!("{backup}.{index}");
+        index += 1;
+    }
+    path
+}
+
+fn read_layout(config_path: &String) -> Result<Layout, String> {
+    match config_format::read(config_path) {
+        Ok(file) => Ok(to_layout(file)),
+        Err(error) => Err(format!("cannot read {config_path}: {error}"))
+    }
+}
+
+fn to_layout(file: ConfigFile) -> Layout {
+    file.config_groups.iter()
+        .map(|group| (group.group_name(), group.config_attributes().into_iter().map(|attribute| (attribute.name, attribute.value)).collect()))
+        .collect()
+}
+
+#[allow(unused_must_use)]
+fn to_config_file(layout: &Layout, config_path: &String) -> ConfigFile {
+    let mut file = ConfigFile::new(config_path.clone());
+    for (index, (group, attributes)) in layout.iter().enumerate() {
+        file.add_config_group(group.clone());
+        for (key, value) in attributes {
+            if let Ok(attribute) = ConfigAttribute::new(key.clone(), value.clone()) {
+                file.config_groups[index].add_config_attribute(attribute);
+            }
+        }
+    }
+    file
+}
@@ -0,0 +1,447 @@
+use std::cmp::Reverse;
+use std::collections::HashMap;
+use std::env;
+use std::fmt;
+use std::str::FromStr;
+
+use config_1lt::data::config_group::ConfigGroup;
+
+use crate::config_format;
+use crate::paths;
+
+const ENV_PREFIX: &str = "NOWPLAYING_";
+
+//A problem in the config file: an invalid value (error) or an unknown key (warning)
+#[derive(Clone)]
+pub struct ConfigIssue {
+    pub group: String,
+    pub key: String,
+    //"line 12" in the config file or the environment variable, None if it could not be found
+    pub location: Option<String>,
+    pub message: String,
+    pub hint: String
+}
+
+impl ConfigIssue {
+    //a problem with the whole file, like a missing file
+    pub fn file(message: &str, hint: &str) -> ConfigIssue {
+        ConfigIssue {
+            group: String::new(),
+            key: String::new(),
+            location: None,
+            message: message.to_string(),
+            hint: hint.to_string()
+        }
+    }
+}
+
+impl fmt::Display for ConfigIssue {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match (self.group.as_str(), self.key.as_str()) {
+            ("", _) => (),
+            (group, "") => write!(f, "[{group}] ")?,
+            (group, key) => write!(f, "[{group}] {key} ")?
+        };
+        if let Some(location) = &self.location {
+            write!(f, "({location}) ")?;
+        }
+        write!(f, "{}", self.message)?;
+        if self.hint != "" {
+            write!(f, "; {}", self.hint)?;
+        }
+        Ok(())
+    }
+}
+
+//Everything found while reading a config file
+#[derive(Default)]
+pub struct ConfigReport {
+    pub errors: Vec<ConfigIssue>,
+    pub warnings: Vec<ConfigIssue>
+}
+
+//Line numbers of groups and keys, the config library does not keep them
+pub struct LineIndex {
+    lines: HashMap<(String, String), usize>
+}
+
+impl LineIndex {
+    pub fn new(text: &str) -> LineIndex {
+        if text.trim_start().starts_with('{') {
+            return LineIndex::from_json(text)
+        }
+        let mut lines = HashMap::new();
+        let mut group = String::new();
+        let mut key_prefix = String::new();
+        for (index, line) in text.lines().enumerate() {
+            let line = line.trim();
+            if line.starts_with('[') && line.ends_with(']') {
+                (group, key_prefix) = config_format::legacy_group(line[1..line.len() - 1].trim());
+                lines.entry((group.clone(), String::new())).or_insert(index + 1);
+            } else if let Some(position) = line.find(|c| c == '=' || c == ':') {
+                lines.entry((group.clone(), format!("{key_prefix}{}", line[..position].trim()))).or_insert(index + 1);
+            }
+        }
+        LineIndex { lines }
+    }
+
+    //JSON as config convert writes it, one key per line, an object on one line only gets the line of its key
+    fn from_json(text: &str) -> LineIndex {
+        let mut lines = HashMap::new();
+        //open objects (true) and arrays with their key, "" for the file itself and array entries
+        let mut open: Vec<(String, bool)> = Vec::new();
+        let mut group = String::new();
+        let mut key_prefix = String::new();
+        for (index, line) in text.lines().enumerate() {
+            let line = line.trim();
+            if line.starts_with('}') || line.starts_with(']') {
+                open.pop();
+                (group, key_prefix) = LineIndex::json_group(&open);
+                continue
+            }
+            let (key, rest) = match line.strip_prefix('"').and_then(|line| line.split_once("\":")) {
+                Some((key, rest)) => (key, rest.trim()),
+                None => ("", line)
+            };
+            let in_object = open.last().map(|(_key, object)| *object).unwrap_or(false);
+            if key != "" && in_object {
+                lines.entry((group.clone(), format!("{key_prefix}{key}"))).or_insert(index + 1);
+            }
+            if rest.ends_with('{') || rest.ends_with('[') {
+                open.push((key.to_string(), rest.ends_with('{')));
+                (group, key_prefix) = LineIndex::json_group(&open);
+                if key != "" && rest.ends_with('{') {
+                    lines.entry((group.clone(), String::new())).or_insert(index + 1);
+                }
+            }
+        }
+        LineIndex { lines }
+    }
+
+    //the named objects around a line are the table header of TOML, "profile": { "chill": { is [profile.chill]
+    fn json_group(open: &[(String, bool)]) -> (String, String) {
+        let header: Vec<&str> = open.iter().map(|(key, _object)| key.as_str()).filter(|key| *key != "").collect();
+        config_format::legacy_group(&header.join("."))
+    }
+
+    pub fn line(&self, group: &str, key: &str) -> Option<String> {
+        self.lines.get(&(group.to_string(), key.to_string())).map(|line| format!("line {line}"))
+    }
+}
+
+//Values that win over the config file: NOWPLAYING_<GROUP>_<KEY> environment variables, then the active profile
+pub struct Overrides {
+    values: Vec<OverrideValue>
+}
+
+struct OverrideValue {
+    group: String,
+    key: String,
+    //the environment variable or the line in the profile
+    location: String,
+    value: String
+}
+
+impl Overrides {
+    //groups are matched longest first, NOWPLAYING_WEB_FILES_WEB_FILE3 is web_file3 in [web_files]
+    pub fn from_env(groups: &[String], report: &mut ConfigReport) -> Overrides {
+        let mut groups = groups.to_vec();
+        groups.sort_by_key(|group| Reverse(group.len()));
+        let mut values = Vec::new();
+        for (variable, value) in env::vars_os().filter_map(|(variable, value)| Some((variable.into_string().ok()?, value.into_string().ok()?))) {
+            let name = match variable.strip_prefix(ENV_PREFIX) {
+                Some(name) if variable != paths::ENV_CONFIG && variable != paths::ENV_DATA_DIR => name.to_lowercase(),
+                _ => continue
+            };
+            match groups.iter().find_map(|group| Some((group, name.strip_prefix(&format!("{group}_"))?))) {
+                Some((group, key)) if key != "" => values.push(OverrideValue { group: group.clone(), key: key.to_string(), location: variable, value }),
+                _ => report.warnings.push(ConfigIssue {
+                    group: String::new(),
+                    key: String::new(),
+                    location: Some(variable),
+                    message: "environment variable does not name a config group and key, it is ignored".to_string(),
+                    hint: "use NOWPLAYING_<GROUP>_<KEY>, e.g. NOWPLAYING_GENERAL_PORT".to_string()
+                })
+            }
+        }
+        Overrides { values }
+    }
+
+    //keys of [profile.<name>] are <group>.<key>, the environment still wins over them
+    pub fn add_profile(&mut self, profile: &ConfigGroup, lines: &LineIndex, report: &mut ConfigReport) {
+        let name = profile.group_name();
+        for attribute in profile.config_attributes() {
+            let location = match lines.line(&name, &attribute.name) {
+                Some(line) => format!("[{name}] {line}"),
+                None => format!("[{name}]")
+            };
+            match attribute.name.split_once('.') {
+                Some((group, key)) if group != "" && key != "" => self.values.push(OverrideValue {
+                    group: group.to_string(),
+                    key: key.to_string(),
+                    location,
+                    value: attribute.value
+                }),
+                _ => report.errors.push(ConfigIssue {
+                    group: name.clone(),
+                    key: attribute.name.clone(),
+                    location: lines.line(&name, &attribute.name),
+                    message: "profile keys name a group and a key".to_string(),
+                    hint: "use <group>.<key>, e.g. general.template".to_string()
+                })
+            }
+        }
+    }
+
+    pub fn value(&self, group: &str, key: &str) -> Option<String> {
+        self.get(group, key).map(|value| value.value.clone())
+    }
+
+    pub fn groups(&self) -> Vec<String> {
+        let mut groups: Vec<String> = Vec::new();
+        for value in &self.values {
+            if !groups.contains(&value.group) {
+                groups.push(value.group.clone());
+            }
+        }
+        groups
+    }
+
+    fn get(&self, group: &str, key: &str) -> Option<&OverrideValue> {
+        self.values.iter().find(|value| value.group == group && value.key == key)
+    }
+
+    fn group_values<'a>(&'a self, group: &'a str) -> impl Iterator<Item = &'a OverrideValue> {
+        self.values.iter().filter(move |value| value.group == group)
+    }
+}
+
+//Reads typed values out of one config group, problems are collected in the report instead of panicking
+pub struct GroupReader<'a> {
+    group: &'a ConfigGroup,
+    name: String,
+    lines: &'a LineIndex,
+    overrides: &'a Overrides,
+    report: &'a mut ConfigReport,
+    //every key that was asked for, present or not, and prefixes of numbered keys like web_file0
+    known: Vec<String>,
+    known_prefixes: Vec<String>
+}
+
+impl<'a> GroupReader<'a> {
+    pub fn new(group: &'a ConfigGroup, lines: &'a LineIndex, overrides: &'a Overrides, report: &'a mut ConfigReport) -> GroupReader<'a> {
+        GroupReader {
+            group,
+            name: group.group_name(),
+            lines,
+            overrides,
+            report,
+            known: Vec::new(),
+            known_prefixes: Vec::new()
+        }
+    }
+
+    fn value(&mut self, key: &str) -> Option<String> {
+        self.known.push(key.to_string());
+        if let Some(override_value) = self.overrides.get(&self.name, key) {
+            return Some(override_value.value.clone())
+        }
+        self.group.get_config_attribute(key.to_string()).ok().map(|attribute| attribute.value)
+    }
+
+    pub fn string(&mut self, key: &str, default: String) -> String {
+        self.value(key).unwrap_or(default)
+    }
+
+    //true/false, yes/no, on/off and 1/0
+    pub fn bool(&mut self, key: &str, default: bool) -> bool {
+        let value = match self.value(key) {
+            Some(value) => value,
+            None => return default
+        };
+        match parse_bool(&value) {
+            Some(value) => value,
+            None => {
+                self.error(key, &format!("\"{value}\" is not a boolean"), "use true or false (yes/no, on/off and 1/0 work too)");
+                default
+            }
+        }
+    }
+
+    pub fn number<T: FromStr + fmt::Display>(&mut self, key: &str, default: T) -> T {
+        let value = match self.value(key) {
+            Some(value) => value,
+            None => return default
+        };
+        match value.trim().parse::<T>() {
+            Ok(number) => number,
+            Err(_error) => {
+                self.error(key, &format!("\"{value}\" is not a valid number"), &format!("expected a whole number like {default}"));
+                default
+            }
+        }
+    }
+
+    pub fn choice(&mut self, key: &str, default: String, choices: &[&str]) -> String {
+        let value = match self.value(key) {
+            Some(value) => value,
+            None => return default
+        };
+        let choice = value.trim().to_lowercase();
+        match choices.contains(&choice.as_str()) {
+            true => choice,
+            false => {
+                self.error(key, &format!("\"{value}\" is not allowed"), &format!("use one of {}", choices.join(", ")));
+                default
+            }
+        }
+    }
+
+    //(name, value) of keys containing prefix, like web_file0, web_file1, ...
+    pub fn numbered(&mut self, prefix: &str) -> Vec<(String, String)> {
+        self.known_prefixes.push(prefix.to_string());
+        let mut attributes: Vec<(String, String)> = self.group.config_attributes().into_iter()
+            .filter(|attribute| attribute.name.contains(prefix))
+            .map(|attribute| match self.overrides.get(&self.name, &attribute.name) {
+                Some(override_value) => (attribute.name, override_value.value.clone()),
+                None => (attribute.name, attribute.value)
+            })
+            .collect();
+        for override_value in self.overrides.group_values(&self.name).filter(|override_value| override_value.key.contains(prefix)) {
+            if !attributes.iter().any(|(name, _value)| *name == override_value.key) {
+                attributes.push((override_value.key.clone(), override_value.value.clone()));
+            }
+        }
+        attributes
+    }
+
+    pub fn error(&mut self, key: &str, message: &str, hint: &str) {
+        let issue = self.issue(key, message, hint);
+        self.report.errors.push(issue);
+    }
+
+    pub fn warning(&mut self, key: &str, message: &str, hint: &str) {
+        let issue = self.issue(key, message, hint);
+        self.report.warnings.push(issue);
+    }
+
+    fn issue(&self, key: &str, message: &str, hint: &str) -> ConfigIssue {
+        ConfigIssue {
+            group: self.name.clone(),
+            key: key.to_string(),
+            location: match self.overrides.get(&self.name, key) {
+                Some(override_value) => Some(override_value.location.clone()),
+                None => self.lines.line(&self.name, key).or(self.lines.line(&self.name, ""))
+            },
+            message: message.to_string(),
+            hint: hint.to_string()
+        }
+    }
+
+    //Warns about keys nobody asked for, most likely typos
+    pub fn finish(self) {
+        let mut names: Vec<String> = self.group.config_attributes().into_iter().map(|attribute| attribute.name).collect();
+        for override_value in self.overrides.group_values(&self.name) {
+            if !names.contains(&override_value.key) {
+                names.push(override_value.key.clone());
+            }
+        }
+        for name in names {
+            let known = self.known.contains(&name) || self.known_prefixes.iter().any(|prefix| name.contains(prefix.as_str()));
+            if known {
+                continue;
+            }
+            let hint = match self.known.iter().min_by_key(|key| edit_distance(key, &name)) {
+                Some(key) if edit_distance(key, &name) <= 3 => format!("did you mean {key}?"),
+                _ => format!("known keys: {}", self.known.join(", "))
+            };
+            let issue = self.issue(&name, "unknown key, it is ignored", &hint);
+            self.report.warnings.push(issue);
+        }
+    }
+}
+
+//A group nobody reads, most likely a typo in the group name
+pub fn unknown_group(name: &str, lines: &LineIndex, known_groups: &[&str]) -> ConfigIssue {
+    let hint = match known_groups.iter().min_by_key(|group| edit_distance(group, name)) {
+        Some(group) if edit_distance(group, name) <= 3 => format!("did you mean [{group}]?"),
+        _ => format!("known groups: {}", known_groups.join(", "))
+    };
+    ConfigIssue {
+        group: name.to_string(),
+        key: String::new(),
+        location: lines.line(name, ""),
+        message: "unknown group, it is ignored".to_string(),
+        hint
+    }
+}
+
+pub fn parse_bool(value: &str) -> Option<bool> {
+    match value.trim().to_lowercase().as_str() {
+        "true" | "yes" | "on" | "1" => Some(true),
+        "false" | "no" | "off" | "0" => Some(false),
+        _ => None
+    }
+}
+
+//Levenshtein distance, for "did you mean" hints
+fn edit_distance(a: &str, b: &str) -> usize {
+    let b: Vec<char> = b.chars().collect();
+    let mut previous: Vec<usize> = (0..=b.len()).collect();
+    for (i, a_char) in a.chars().enumerate() {
+        let mut current = vec![i + 1];
+        for (j, b_char) in b.iter().enumerate() {
+            let substitution = previous[j] + if a_char == *b_char { 0 } else { 1 };
+            current.push(substitution.min(previous[j + 1] + 1).min(current[j] + 1));
+        }
+        previous = current;
+    }
+    previous[b.len()]
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn json_keys_have_lines() {
+        let json = r#"{
+  "general": {
+    "port": 8080,
+    "web_file": [
+      "/index.html"
+    ]
+  },
+  "profile": {
+    "chill": {
+      "general": {
+        "template": "chill"
+      }
+    }
+  },
+  "webhook_x": {
+    "url": "http://localhost",
+    "headers": {
+      "Authorization": "Bearer x"
+    },
+    "retries": 3
+  }
+}
+"#;
+        let lines = LineIndex::new(json);
+        assert_eq!(lines.line("general", ""), Some("line 2".to_string()));
+        assert_eq!(lines.line("general", "port"), Some("line 3".to_string()));
+        assert_eq!(lines.line("profile.chill", ""), Some("line 9".to_string()));
+        assert_eq!(lines.line("profile.chill", "general.template"), Some("line 11".to_string()));
+        assert_eq!(lines.line("webhook_x", "url"), Some("line 16".to_string()));
+        assert_eq!(lines.line("webhook_x", "retries"), Some("line 20".to_string()));
+    }
+
+    #[test]
+    fn toml_keys_have_lines() {
+        let lines = LineIndex::new("[general]\nport = 8080\n\n[profile.chill.general]\ntemplate = \"chill\"\n[webhook.x]\nurl = \"http://localhost\"\n");
+        assert_eq!(lines.line("general", "port"), Some("line 2".to_string()));
+        assert_eq!(lines.line("profile.chill", "general.template"), Some("line 5".to_string()));
+        assert_eq!(lines.line("webhook_x", "url"), Some("line 7".to_string()));
+    }
+}
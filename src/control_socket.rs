@@ -0,0 +1,189 @@
+use std::fs;
+use std::io::{prelude::*, BufReader};
+use std::os::unix::net::{UnixListener, UnixStream};
+use std::path::PathBuf;
+use std::sync::mpsc::Receiver;
+use std::sync::{Arc, Mutex};
+use std::{thread, time};
+
+use log::error;
+use serde_json::{json, Value};
+
+use crate::config::Config;
+use crate::history::PlayHistory;
+use crate::mpris_mediaplayer2;
+use crate::nowplaying::{SharedNowplayingData, TrackEvent};
+use crate::paths;
+
+const DEFAULT_HISTORY_LIMIT: usize = 5;
+const WRITE_TIMEOUT: time::Duration = time::Duration::from_secs(1);
+
+type Client = Arc<Mutex<UnixStream>>;
+
+//Unix socket speaking a line based JSON protocol: get, subscribe, history and control
+pub struct ControlSocket {
+    path: PathBuf,
+    state: SharedNowplayingData,
+    history: Arc<PlayHistory>,
+    subscribers: Arc<Mutex<Vec<Client>>>
+}
+
+impl ControlSocket {
+    pub fn new(config: &Config, state: SharedNowplayingData, history: PlayHistory) -> ControlSocket {
+        let path = match config.socket_path.as_str() {
+            "" => default_socket_path(),
+            path => PathBuf::from(path)
+        };
+        ControlSocket {
+            path,
+            state,
+            history: Arc::new(history),
+            subscribers: Arc::new(Mutex::new(Vec::new()))
+        }
+    }
+
+    pub fn start(self, rx: Receiver<TrackEvent>) {
+        //a socket file left over from a previous run would make bind fail, one of a running instance is kept
+        if self.path.exists() {
+            if UnixStream::connect(&self.path).is_ok() {
+                error!("Control socket {} is used by another running instance", self.path.display());
+                return
+            }
+            let _ = fs::remove_file(&self.path);
+        }
+        //only this user may control playback, the socket may be in the shared temp directory,
+        //so it is created with 0600 right away instead of being restricted after bind
+        let old_umask = unsafe { libc::umask(0o177) };
+        let listener = UnixListener::bind(&self.path);
+        unsafe { libc::umask(old_umask) };
+        let listener = match listener {
+            Ok(listener) => listener,
+            Err(error) => {
+                error!("Cannot bind control socket {}: {}", self.path.display(), error);
+                return
+            }
+        };
+
+        let state = Arc::clone(&self.state);
+        let history = Arc::clone(&self.history);
+        let subscribers = Arc::clone(&self.subscribers);
+        thread::spawn(move || {
+            for stream in listener.incoming() {
+                let stream = match stream {
+                    Ok(stream) => stream,
+                    Err(_error) => continue
+                };
+                let (state, history, subscribers) = (Arc::clone(&state), Arc::clone(&history), Arc::clone(&subscribers));
+                thread::spawn(move || handle_client(stream, state, history, subscribers));
+            }
+        });
+
+        for event in rx {
+            let line = json!({"event": event.kind.name(), "state": *self.state.lock().unwrap()}).to_string();
+            //drop subscribers that disconnected or stopped reading
+            self.subscribers.lock().unwrap().retain(|client| write_line(client, &line).is_ok());
+        }
+
+        //the service is shutting down
+        let line = json!({"event": "shutdown"}).to_string();
+        for client in self.subscribers.lock().unwrap().iter() {
+            let _ = write_line(client, &line);
+        }
+        let _ = fs::remove_file(&self.path);
+    }
+}
+
+fn handle_client(stream: UnixStream, state: SharedNowplayingData, history: Arc<PlayHistory>, subscribers: Arc<Mutex<Vec<Client>>>) {
+    let reader = match stream.try_clone() {
+        Ok(reader) => BufReader::new(reader),
+        Err(_error) => return
+    };
+    let _ = stream.set_write_timeout(Some(WRITE_TIMEOUT));
+    let client: Client = Arc::new(Mutex::new(stream));
+
+    for line in reader.lines() {
+        let line = match line {
+            Ok(line) => line,
+            Err(_error) => return
+        };
+        if line.trim() == "" {
+            continue;
+        }
+        let response = match parse_request(&line) {
+            Ok(request) => handle_request(&request, &client, &state, &history, &subscribers),
+            Err(error) => error_response(&error)
+        };
+        if write_line(&client, &response.to_string()).is_err() {
+            return
+        }
+    }
+}
+
+//Accepts {"command": "history", "limit": 10} as well as the short form "history 10"
+fn parse_request(line: &str) -> Result<Value, String> {
+    let line = line.trim();
+    if line.starts_with('{') {
+        return serde_json::from_str(line).map_err(|error| format!("invalid JSON: {error}"))
+    }
+    let mut words = line.split_whitespace();
+    let command = words.next().unwrap_or_default();
+    let argument = words.next();
+    let request = match (command, argument) {
+        ("history", Some(limit)) => json!({"command": command, "limit": limit.parse::<usize>().map_err(|_error| "limit must be a positive number")?}),
+        ("control", Some(action)) => json!({"command": command, "action": action}),
+        _ => json!({"command": command})
+    };
+    Ok(request)
+}
+
+fn handle_request(request: &Value, client: &Client, state: &SharedNowplayingData, history: &PlayHistory, subscribers: &Arc<Mutex<Vec<Client>>>) -> Value {
+    match request["command"].as_str().unwrap_or_default() {
+        "get" => json!({"ok": true, "state": *state.lock().unwrap()}),
+        "subscribe" => {
+            subscribers.lock().unwrap().push(Arc::clone(client));
+            json!({"ok": true, "state": *state.lock().unwrap()})
+        }
+        "history" => {
+            let limit = match &request["limit"] {
+                Value::Null => DEFAULT_HISTORY_LIMIT,
+                limit => match limit.as_u64() {
+                    Some(limit) => limit as usize,
+                    None => return error_response("limit must be a positive number")
+                }
+            };
+            match history.last_records(limit) {
+                Ok(records) => json!({"ok": true, "history": records}),
+                Err(error) => error_response(&format!("cannot read play history: {error}"))
+            }
+        }
+        "control" => {
+            let action = request["action"].as_str().unwrap_or_default();
+            let player = state.lock().unwrap().player.clone();
+            let player = match player.as_str() {
+                "" => match mpris_mediaplayer2::get_media_players() {
+                    Ok(players) if !players.is_empty() => players[0].clone(),
+                    _ => return error_response("no mediaplayer found")
+                },
+                _ => player
+            };
+            match mpris_mediaplayer2::control_media_player(&player, action) {
+                Ok(()) => json!({"ok": true}),
+                Err(error) => error_response(&error.to_string())
+            }
+        }
+        command => error_response(&format!("unknown command: {command}"))
+    }
+}
+
+fn error_response(error: &str) -> Value {
+    json!({"ok": false, "error": error})
+}
+
+fn write_line(client: &Client, line: &str) -> Result<(), std::io::Error> {
+    client.lock().unwrap().write_all(format!("{line}\n").as_bytes())
+}
+
+//$XDG_RUNTIME_DIR/1lt_nowplaying.sock
+fn default_socket_path() -> PathBuf {
+    paths::runtime_directory().join("1lt_nowplaying.sock")
+}
@@ -0,0 +1,48 @@
+use std::fs;
+use std::io::Read;
+use std::path::{Path, PathBuf};
+use std::time;
+
+use log::warn;
+
+use crate::mpris_mediaplayer2::PlayerMetadata;
+use crate::web_display::percent_decode;
+
+//a slow cover server must not block the output that shows the cover
+const CONNECT_TIMEOUT: time::Duration = time::Duration::from_millis(3000);
+const DOWNLOAD_TIMEOUT: time::Duration = time::Duration::from_millis(10000);
+
+//Returns a local file with the cover art of the track.
+//Remote covers (http/https) are downloaded into the cache directory as <file_name>.
+pub fn local_cover_path(metadata: &PlayerMetadata, cache_directory: &Path, file_name: &str) -> Option<PathBuf> {
+    let art_url = metadata.fields.get("mpris:artUrl")?;
+    if let Some(path) = art_url.strip_prefix("file://") {
+        return Some(PathBuf::from(percent_decode(path, false)))
+    }
+    if art_url.starts_with("http://") || art_url.starts_with("https://") {
+        let path = cache_directory.join(file_name);
+        return match download(art_url, &path) {
+            Ok(()) => Some(path),
+            Err(error) => {
+                warn!("Cannot download cover art {art_url}: {error}");
+                None
+            }
+        }
+    }
+    None
+}
+
+fn download(url: &str, path: &Path) -> Result<(), Box<dyn std::error::Error>> {
+    if let Some(directory) = path.parent() {
+        fs::create_dir_all(directory)?;
+    }
+    let agent = ureq::AgentBuilder::new()
+        .timeout_connect(CONNECT_TIMEOUT)
+        .timeout(DOWNLOAD_TIMEOUT)
+        .build();
+    let response = agent.get(url).call()?;
+    let mut content: Vec<u8> = Vec::new();
+    response.into_reader().read_to_end(&mut content)?;
+    fs::write(path, content)?;
+    Ok(())
+}
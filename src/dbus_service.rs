@@ -0,0 +1,163 @@
+use std::collections::HashMap;
+use std::sync::mpsc::{Receiver, TryRecvError};
+use std::time::Duration;
+
+use dbus::arg::{PropMap, RefArg, Variant};
+use dbus::blocking::stdintf::org_freedesktop_dbus::PropertiesPropertiesChanged;
+use dbus::blocking::Connection;
+use dbus::channel::{MatchingReceiver, Sender};
+use dbus::message::{MatchRule, SignalArgs};
+use dbus::{MethodErr, Path};
+use dbus_crossroads::{Crossroads, IfaceBuilder};
+use log::error;
+
+use crate::config::Config;
+use crate::mpris_mediaplayer2;
+use crate::nowplaying::{NowplayingData, SharedNowplayingData, TrackEvent};
+
+const OBJECT_PATH: &str = "/dev/lt1/NowPlaying";
+const INTERFACE: &str = "dev.lt1.NowPlaying";
+const PROCESS_INTERVAL: Duration = Duration::from_millis(200);
+
+//Session bus service exposing the now playing state (the D-Bus equivalent of /nowplaying)
+pub struct DbusService {
+    bus_name: String,
+    state: SharedNowplayingData
+}
+
+impl DbusService {
+    pub fn new(config: &Config, state: SharedNowplayingData) -> DbusService {
+        DbusService {
+            bus_name: config.dbus_name.clone(),
+            state
+        }
+    }
+
+    pub fn start(self, rx: Receiver<TrackEvent>) {
+        if let Err(error) = self.serve(rx) {
+            error!("D-Bus service {} stopped: {}", self.bus_name, error);
+        }
+    }
+
+    fn serve(&self, rx: Receiver<TrackEvent>) -> Result<(), Box<dyn std::error::Error>> {
+        let session = Connection::new_session()?;
+        session.request_name(self.bus_name.as_str(), false, true, true)?;
+
+        let mut crossroads = Crossroads::new();
+        let token = crossroads.register(INTERFACE, |b: &mut IfaceBuilder<SharedNowplayingData>| {
+            b.property("Player").get(|_, state| Ok(state.lock().unwrap().player.clone()));
+            b.property("Status").get(|_, state| Ok(state.lock().unwrap().status.clone()));
+            b.property("Artist").get(|_, state| Ok(state.lock().unwrap().metadata.artist.trim().to_string()));
+            b.property("Title").get(|_, state| Ok(state.lock().unwrap().metadata.title.clone()));
+            b.property("Album").get(|_, state| Ok(state.lock().unwrap().metadata.album.clone()));
+            b.property("LengthMs").get(|_, state| Ok(state.lock().unwrap().metadata.length_ms));
+            b.property("Metadata").get(|_, state| Ok(metadata_fields(&state.lock().unwrap())));
+            b.property("SelectedPlayer").get(|_, state| Ok(state.lock().unwrap().selected_player.clone().unwrap_or_default()));
+            b.property("Profile").get(|_, state| Ok(state.lock().unwrap().profile.clone()));
+            b.property("Profiles").get(|_, state| Ok(state.lock().unwrap().profiles.clone()));
+
+            b.method("SelectPlayer", ("player",), (), |ctx, state, (player,): (String,)| {
+                let player = player.trim_start_matches("org.mpris.MediaPlayer2.").to_string();
+                if player == "" {
+                    return Err(MethodErr::invalid_arg("player must not be empty"))
+                }
+                state.lock().unwrap().selected_player = Some(player.clone());
+                ctx.push_msg(selection_changed_message(&player));
+                Ok(())
+            });
+            b.method("ClearPlayerSelection", (), (), |ctx, state, ()| {
+                state.lock().unwrap().selected_player = None;
+                ctx.push_msg(selection_changed_message(""));
+                Ok(())
+            });
+            //applied by the service at its next iteration, Profile changes once it is active
+            b.method("SelectProfile", ("profile",), (), |_, state, (profile,): (String,)| {
+                let mut state = state.lock().unwrap();
+                if profile != "" && !state.profiles.contains(&profile) {
+                    return Err(MethodErr::invalid_arg(&format!("unknown profile {profile}")))
+                }
+                state.requested_profile = Some(profile);
+                Ok(())
+            });
+            b.method("ListPlayers", (), ("players",), |_, _, ()| {
+                match mpris_mediaplayer2::get_media_players() {
+                    Ok(players) => Ok((players,)),
+                    Err(error) => Err(MethodErr::failed(&error))
+                }
+            });
+        });
+        crossroads.insert(OBJECT_PATH, &[token], self.state.clone());
+        session.start_receive(MatchRule::new_method_call(), Box::new(move |message, connection| {
+            let _ = crossroads.handle_message(message, connection);
+            true
+        }));
+
+        let mut profile = self.state.lock().unwrap().profile.clone();
+        loop {
+            session.process(PROCESS_INTERVAL)?;
+            let active_profile = self.state.lock().unwrap().profile.clone();
+            if active_profile != profile {
+                let _ = session.send(profile_changed_message(&active_profile));
+                profile = active_profile;
+            }
+            match rx.try_recv() {
+                Ok(_event) => {
+                    let state = self.state.lock().unwrap().clone();
+                    let _ = session.send(state_changed_message(&state));
+                }
+                Err(TryRecvError::Empty) => (),
+                Err(TryRecvError::Disconnected) => return Ok(())
+            }
+        }
+    }
+}
+
+//SelectedPlayer of an already running service, None if it is not running or nothing is selected
+pub fn get_selected_player(bus_name: &str) -> Option<String> {
+    let session = Connection::new_session().ok()?;
+    let proxy = session.with_proxy(bus_name, OBJECT_PATH, Duration::from_millis(1000));
+    use dbus::blocking::stdintf::org_freedesktop_dbus::Properties;
+    let selected_player: String = proxy.get(INTERFACE, "SelectedPlayer").ok()?;
+    match selected_player.as_str() {
+        "" => None,
+        _ => Some(selected_player)
+    }
+}
+
+fn metadata_fields(state: &NowplayingData) -> HashMap<String, String> {
+    state.metadata.fields.iter().map(|(key, value)| (key.clone(), value.clone())).collect()
+}
+
+fn properties_changed_message(changed_properties: PropMap) -> dbus::Message {
+    let signal = PropertiesPropertiesChanged {
+        interface_name: INTERFACE.to_string(),
+        changed_properties,
+        invalidated_properties: Vec::new()
+    };
+    signal.to_emit_message(&Path::from(OBJECT_PATH))
+}
+
+fn state_changed_message(state: &NowplayingData) -> dbus::Message {
+    let mut changed: PropMap = HashMap::new();
+    let mut insert = |name: &str, value: Box<dyn RefArg>| changed.insert(name.to_string(), Variant(value));
+    insert("Player", Box::new(state.player.clone()));
+    insert("Status", Box::new(state.status.clone()));
+    insert("Artist", Box::new(state.metadata.artist.trim().to_string()));
+    insert("Title", Box::new(state.metadata.title.clone()));
+    insert("Album", Box::new(state.metadata.album.clone()));
+    insert("LengthMs", Box::new(state.metadata.length_ms));
+    insert("Metadata", Box::new(metadata_fields(state)));
+    properties_changed_message(changed)
+}
+
+fn selection_changed_message(player: &str) -> dbus::Message {
+    let mut changed: PropMap = HashMap::new();
+    changed.insert("SelectedPlayer".to_string(), Variant(Box::new(player.to_string())));
+    properties_changed_message(changed)
+}
+
+fn profile_changed_message(profile: &str) -> dbus::Message {
+    let mut changed: PropMap = HashMap::new();
+    changed.insert("Profile".to_string(), Variant(Box::new(profile.to_string())));
+    properties_changed_message(changed)
+}
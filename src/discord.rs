@@ -0,0 +1,182 @@
+use std::io;
+use std::io::prelude::*;
+use std::os::unix::net::UnixStream;
+use std::path::PathBuf;
+use std::process;
+use std::sync::mpsc::{Receiver, RecvTimeoutError};
+use std::time;
+
+use log::warn;
+use serde_json::{json, Value};
+
+use crate::config::Config;
+use crate::history;
+use crate::nowplaying::{TrackEvent, TrackEventKind};
+use crate::paths;
+
+const OP_HANDSHAKE: u32 = 0;
+const OP_FRAME: u32 = 1;
+const OP_CLOSE: u32 = 2;
+const RECONNECT_INTERVAL: time::Duration = time::Duration::from_secs(15);
+const RESPONSE_TIMEOUT: time::Duration = time::Duration::from_secs(5);
+//larger frames are not sent by Discord, the length of a broken frame must not be allocated
+const MAX_FRAME_LENGTH: usize = 64 * 1024;
+
+//Shows the current track as Discord Rich Presence through the local IPC socket
+pub struct DiscordPresence {
+    client_id: String,
+    nonce: u64
+}
+
+impl DiscordPresence {
+    pub fn new(config: &Config) -> DiscordPresence {
+        DiscordPresence {
+            client_id: config.discord.client_id.clone(),
+            nonce: 0
+        }
+    }
+
+    pub fn start(mut self, rx: Receiver<TrackEvent>) {
+        let mut connection: Option<UnixStream> = None;
+        let mut last_event: Option<TrackEvent> = None;
+        loop {
+            let event = match rx.recv_timeout(RECONNECT_INTERVAL) {
+                Ok(event) => Some(event),
+                Err(RecvTimeoutError::Timeout) => None,
+                Err(RecvTimeoutError::Disconnected) => return
+            };
+            if event.is_some() {
+                last_event = event.clone();
+            }
+
+            //Discord may have been (re)started, restore the activity once connected
+            let reconnected = connection.is_none();
+            if connection.is_none() {
+                connection = self.connect().ok();
+            }
+            let stream = match &mut connection {
+                Some(stream) => stream,
+                None => continue
+            };
+            let update = match (&event, reconnected) {
+                (Some(event), _) => Some(event),
+                (None, true) => last_event.as_ref(),
+                (None, false) => None
+            };
+            if let Some(update) = update {
+                let activity = DiscordPresence::activity(update);
+                if let Err(error) = self.set_activity(stream, activity) {
+                    warn!("Lost connection to Discord: {}", error);
+                    connection = None;
+                }
+            }
+        }
+    }
+
+    fn connect(&self) -> Result<UnixStream, io::Error> {
+        let mut last_error = io::Error::new(io::ErrorKind::NotFound, "no Discord IPC socket found");
+        for path in ipc_socket_paths() {
+            let mut stream = match UnixStream::connect(&path) {
+                Ok(stream) => stream,
+                Err(error) => {
+                    last_error = error;
+                    continue;
+                }
+            };
+            stream.set_read_timeout(Some(RESPONSE_TIMEOUT))?;
+            write_frame(&mut stream, OP_HANDSHAKE, &json!({"v": 1, "client_id": self.client_id}))?;
+            let (op, ready) = read_frame(&mut stream)?;
+            if op == OP_CLOSE || ready["evt"] != "READY" {
+                return Err(io::Error::new(io::ErrorKind::ConnectionRefused, format!("Discord refused the handshake: {}", ready["message"])))
+            }
+            return Ok(stream)
+        }
+        Err(last_error)
+    }
+
+    //None clears the activity
+    fn activity(event: &TrackEvent) -> Option<Value> {
+        let artist = event.metadata.artist.trim();
+        //type 2 is shown as "Listening to" instead of "Playing"
+        let mut activity = json!({
+            "type": 2,
+            "details": event.metadata.title,
+            "state": if artist != "" { format!("by {artist}") } else { event.player.clone() },
+            "assets": {"large_text": if event.metadata.album != "" { event.metadata.album.clone() } else { event.player.clone() }}
+        });
+        if let Some(art_url) = event.metadata.fields.get("mpris:artUrl") {
+            if art_url.starts_with("https://") {
+                activity["assets"]["large_image"] = json!(art_url);
+            }
+        }
+
+        match event.kind {
+            TrackEventKind::Stopped => return None,
+            TrackEventKind::Paused => {
+                activity["state"] = json!(format!("{} (paused)", activity["state"].as_str().unwrap_or_default()));
+            }
+            TrackEventKind::Changed | TrackEventKind::Resumed => {
+                //elapsed/remaining time from the playback position and track length
+                let start = history::unix_timestamp() as i64 * 1000 - event.position_ms as i64;
+                activity["timestamps"] = json!({"start": start});
+                if event.metadata.length_ms > 0 {
+                    activity["timestamps"]["end"] = json!(start + event.metadata.length_ms as i64);
+                }
+            }
+        }
+        Some(activity)
+    }
+
+    fn set_activity(&mut self, stream: &mut UnixStream, activity: Option<Value>) -> Result<(), io::Error> {
+        self.nonce += 1;
+        let command = json!({
+            "cmd": "SET_ACTIVITY",
+            "args": {"pid": process::id(), "activity": activity},
+            "nonce": self.nonce.to_string()
+        });
+        write_frame(stream, OP_FRAME, &command)?;
+        let (op, response) = read_frame(stream)?;
+        if op == OP_CLOSE {
+            return Err(io::Error::new(io::ErrorKind::ConnectionAborted, "Discord closed the connection"))
+        }
+        if response["evt"] == "ERROR" {
+            warn!("Discord rejected the activity: {}", response["data"]["message"]);
+        }
+        Ok(())
+    }
+}
+
+//discord-ipc-0..9 in $XDG_RUNTIME_DIR (and the flatpak/snap locations)
+fn ipc_socket_paths() -> Vec<PathBuf> {
+    let runtime_directory = paths::runtime_directory();
+    let mut paths = Vec::new();
+    for subdirectory in ["", "app/com.discordapp.Discord", "snap.discord"] {
+        for index in 0..10 {
+            paths.push(runtime_directory.join(subdirectory).join(format!("discord-ipc-{index}")));
+        }
+    }
+    paths
+}
+
+//frame: opcode (u32 LE), length (u32 LE), JSON payload
+fn write_frame(stream: &mut UnixStream, op: u32, payload: &Value) -> Result<(), io::Error> {
+    let payload = payload.to_string();
+    let mut frame = op.to_le_bytes().to_vec();
+    frame.extend((payload.len() as u32).to_le_bytes());
+    frame.extend(payload.as_bytes());
+    stream.write_all(&frame)
+}
+
+fn read_frame(stream: &mut UnixStream) -> Result<(u32, Value), io::Error> {
+    let mut header = [0u8; 8];
+    stream.read_exact(&mut header)?;
+    let op = u32::from_le_bytes([header[0], header[1], header[2], header[3]]);
+    let length = u32::from_le_bytes([header[4], header[5], header[6], header[7]]) as usize;
+    if length > MAX_FRAME_LENGTH {
+        return Err(io::Error::new(io::ErrorKind::InvalidData, format!("Discord sent a frame of {length} bytes")))
+    }
+    let mut payload = vec![0u8; length];
+    stream.read_exact(&mut payload)?;
+    let payload = serde_json::from_slice(&payload).unwrap_or(Value::Null);
+    Ok((op, payload))
+}
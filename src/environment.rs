@@ -7,6 +7,7 @@ use std::path::PathBuf;
 
 use crate::mpris_mediaplayer2;
 use crate::config::Config;
+use crate::template;
 
 pub struct WorkingEnvironment {
     pub path_work_directory: PathBuf,
@@ -50,7 +51,38 @@ impl WorkingEnvironment {
         Ok(file)
     }
 
-    pub fn write_to_now_playing_file(mut self, metadata: &mpris_mediaplayer2::PlayerMetadata) -> Result<WorkingEnvironment, io::Error> {
+    //template, display and on_exit settings after a config reload
+    pub fn set_config(&mut self, config: Config) {
+        self.config = config;
+    }
+
+    pub fn write_to_now_playing_file(mut self, player: &str, metadata: &mpris_mediaplayer2::PlayerMetadata, previous: &mpris_mediaplayer2::PlayerMetadata) -> Result<WorkingEnvironment, io::Error> {
+        let np_string = if self.config.template != "" {
+            template::render(&self.config.template, &self.config, player, metadata, previous)
+        } else {
+            self.default_now_playing_string(metadata)
+        };
+
+        self.file_now_playing.set_len(0)?;
+        self.file_now_playing.seek(SeekFrom::Start(0))?;
+        self.file_now_playing.write_all(np_string.as_bytes())?;
+        Ok(self)
+    }
+
+    //on_exit: clear the file, write the offline template ({previous} is the last track) or keep the last track
+    pub fn write_on_exit(mut self, player: &str, previous: &mpris_mediaplayer2::PlayerMetadata) -> Result<(), io::Error> {
+        let np_string = match self.config.on_exit.as_str() {
+            "keep" => return Ok(()),
+            "offline" => template::render(&self.config.offline_template, &self.config, player, &mpris_mediaplayer2::PlayerMetadata::new(), previous),
+            _ => String::new()
+        };
+        self.file_now_playing.set_len(0)?;
+        self.file_now_playing.seek(SeekFrom::Start(0))?;
+        self.file_now_playing.write_all(np_string.as_bytes())?;
+        self.file_now_playing.sync_all()
+    }
+
+    fn default_now_playing_string(&self, metadata: &mpris_mediaplayer2::PlayerMetadata) -> String {
         let mut np_string = String::new();
         if metadata.artist != "" && self.config.display_artist {
             np_string = format!("{}", metadata.artist);
@@ -64,10 +96,6 @@ impl WorkingEnvironment {
         if np_string.len() > 0 {
             np_string = format!("{}     ", np_string);
         }
-
-        self.file_now_playing.set_len(0)?;
-        self.file_now_playing.seek(SeekFrom::Start(0))?;
-        self.file_now_playing.write_all(np_string.as_bytes())?;
-        Ok(self)
+        np_string
     }
 }
\ No newline at end of file
@@ -0,0 +1,163 @@
+use std::fs;
+use std::fs::{File, OpenOptions};
+use std::io;
+use std::io::{prelude::*, BufReader};
+use std::path::{Path, PathBuf};
+use std::time::{Instant, SystemTime, UNIX_EPOCH};
+
+use serde::{Deserialize, Serialize};
+
+use crate::config::Config;
+use crate::mpris_mediaplayer2::PlayerMetadata;
+
+const HISTORY_FILE_NAME: &str = "history.jsonl";
+
+#[derive(Clone, Serialize, Deserialize)]
+pub struct PlayRecord {
+    //unix timestamp (seconds) of the track change
+    pub started: u64,
+    pub player: String,
+    //how long the track was actually playing in milliseconds
+    pub played_ms: u64,
+    pub metadata: PlayerMetadata
+}
+
+pub struct PlayHistory {
+    enabled: bool,
+    path_history_file: PathBuf,
+    max_size: u64,
+    max_files: u32,
+    current: Option<PlayRecord>,
+    last_update: Instant
+}
+
+impl PlayHistory {
+    pub fn new(config: &Config, work_directory: &Path) -> PlayHistory {
+        PlayHistory {
+            enabled: config.history_enabled,
+            path_history_file: work_directory.join(HISTORY_FILE_NAME),
+            max_size: config.history_max_size_kb * 1024,
+            max_files: config.history_max_files,
+            current: None,
+            last_update: Instant::now()
+        }
+    }
+
+    //Finishes the record of the previous track and starts a new one
+    pub fn track_changed(&mut self, player: &str, metadata: &PlayerMetadata) -> Result<(), io::Error> {
+        self.finish_current_track()?;
+        self.current = Some(PlayRecord {
+            started: unix_timestamp(),
+            player: player.to_string(),
+            played_ms: 0,
+            metadata: metadata.clone()
+        });
+        self.last_update = Instant::now();
+        Ok(())
+    }
+
+    //Adds the time since the last call to the current track if something is playing
+    pub fn update_played_time(&mut self, playing: bool) {
+        let elapsed = self.last_update.elapsed();
+        self.last_update = Instant::now();
+        if !playing {
+            return
+        }
+        if let Some(record) = &mut self.current {
+            record.played_ms += elapsed.as_millis() as u64;
+        }
+    }
+
+    pub fn finish_current_track(&mut self) -> Result<(), io::Error> {
+        let record = match self.current.take() {
+            Some(record) => record,
+            None => return Ok(())
+        };
+        if !self.enabled {
+            return Ok(())
+        }
+
+        let mut line = serde_json::to_string(&record)?;
+        line.push('\n');
+        self.rotate_if_needed(line.len() as u64)?;
+
+        let mut file = OpenOptions::new().create(true).append(true).open(&self.path_history_file)?;
+        file.write_all(line.as_bytes())?;
+        Ok(())
+    }
+
+    //Returns all recorded tracks (oldest first), including rotated files
+    pub fn records(&self) -> Result<Vec<PlayRecord>, io::Error> {
+        let mut records = Vec::new();
+        for index in (1..=self.max_files).rev() {
+            PlayHistory::read_records_from(&self.rotated_path(index), &mut records)?;
+        }
+        PlayHistory::read_records_from(&self.path_history_file, &mut records)?;
+        Ok(records)
+    }
+
+    fn read_records_from(path: &Path, records: &mut Vec<PlayRecord>) -> Result<(), io::Error> {
+        let file = match File::open(path) {
+            Ok(file) => file,
+            Err(error) if error.kind() == io::ErrorKind::NotFound => return Ok(()),
+            Err(error) => return Err(error)
+        };
+        for line in BufReader::new(file).lines() {
+            let line = line?;
+            //skip broken lines (e.g. from a crash while writing)
+            if let Ok(record) = serde_json::from_str::<PlayRecord>(&line) {
+                records.push(record);
+            }
+        }
+        Ok(())
+    }
+
+    //Returns the last `limit` recorded tracks (oldest first)
+    pub fn last_records(&self, limit: usize) -> Result<Vec<PlayRecord>, io::Error> {
+        let mut records = self.records()?;
+        let skip = records.len().saturating_sub(limit);
+        Ok(records.split_off(skip))
+    }
+
+    //Returns all tracks started at or after the unix timestamp `since` (oldest first)
+    pub fn records_since(&self, since: u64) -> Result<Vec<PlayRecord>, io::Error> {
+        let mut records = self.records()?;
+        records.retain(|record| record.started >= since);
+        Ok(records)
+    }
+
+    fn rotate_if_needed(&self, additional_bytes: u64) -> Result<(), io::Error> {
+        if self.max_size == 0 {
+            return Ok(())
+        }
+        let size = match fs::metadata(&self.path_history_file) {
+            Ok(metadata) => metadata.len(),
+            Err(_error) => return Ok(())
+        };
+        if size + additional_bytes <= self.max_size {
+            return Ok(())
+        }
+
+        if self.max_files == 0 {
+            return fs::remove_file(&self.path_history_file)
+        }
+        for index in (1..self.max_files).rev() {
+            let from = self.rotated_path(index);
+            if from.exists() {
+                fs::rename(&from, self.rotated_path(index + 1))?;
+            }
+        }
+        fs::rename(&self.path_history_file, self.rotated_path(1))
+    }
+
+    fn rotated_path(&self, index: u32) -> PathBuf {
+        PathBuf::from(format!("{}.{}", self.path_history_file.display(), index))
+    }
+}
+
+pub fn unix_timestamp() -> u64 {
+    match SystemTime::now().duration_since(UNIX_EPOCH) {
+        Ok(duration) => duration.as_secs(),
+        Err(_error) => 0
+    }
+}
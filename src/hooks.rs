@@ -0,0 +1,145 @@
+use std::io::prelude::*;
+use std::os::unix::process::CommandExt;
+use std::process::{Child, Command, Stdio};
+use std::sync::mpsc::Receiver;
+use std::{thread, time};
+
+use log::{error, warn};
+
+use crate::config::Config;
+use crate::nowplaying::{TrackEvent, TrackEventKind};
+
+const WAIT_INTERVAL: time::Duration = time::Duration::from_millis(50);
+
+//Runs the configured commands on track events.
+//Metadata is passed as NP_* environment variables and as JSON on stdin.
+pub struct HookRunner {
+    commands: Vec<String>,
+    on_pause: bool,
+    on_stop: bool,
+    timeout: time::Duration
+}
+
+impl HookRunner {
+    pub fn new(config: &Config) -> HookRunner {
+        HookRunner {
+            commands: config.hooks.commands.clone(),
+            on_pause: config.hooks.on_pause,
+            on_stop: config.hooks.on_stop,
+            timeout: config.hooks.timeout
+        }
+    }
+
+    pub fn start(self, rx: Receiver<TrackEvent>) {
+        for event in rx {
+            let wanted = match event.kind {
+                TrackEventKind::Changed => true,
+                TrackEventKind::Paused | TrackEventKind::Resumed => self.on_pause,
+                TrackEventKind::Stopped => self.on_stop
+            };
+            if !wanted {
+                continue;
+            }
+            for command in &self.commands {
+                match HookRunner::spawn_command(command, &event) {
+                    Ok(child) => {
+                        let command = command.clone();
+                        let timeout = self.timeout;
+                        thread::spawn(move || HookRunner::wait_for_command(child, command, timeout));
+                    }
+                    Err(error) => error!("Cannot run hook \"{command}\": {error}")
+                };
+            }
+        }
+    }
+
+    fn spawn_command(command: &str, event: &TrackEvent) -> Result<Child, std::io::Error> {
+        let mut child = Command::new("sh")
+            .arg("-c")
+            .arg(command)
+            .envs(event_variables(event))
+            .stdin(Stdio::piped())
+            .stdout(Stdio::null())
+            //own process group, so a timeout also kills what sh started
+            .process_group(0)
+            .spawn()?;
+
+        //the command may not read stdin at all, so a broken pipe is fine
+        if let Some(mut stdin) = child.stdin.take() {
+            if let Ok(json) = serde_json::to_string(event) {
+                let _ = stdin.write_all(json.as_bytes());
+            }
+        }
+        Ok(child)
+    }
+
+    fn wait_for_command(mut child: Child, command: String, timeout: time::Duration) {
+        let started = time::Instant::now();
+        loop {
+            match child.try_wait() {
+                Ok(Some(status)) => {
+                    if !status.success() {
+                        warn!("Hook \"{command}\" failed: {status}");
+                    }
+                    return
+                }
+                Ok(None) => (),
+                Err(error) => {
+                    error!("Cannot wait for hook \"{command}\": {error}");
+                    return
+                }
+            }
+            if started.elapsed() >= timeout {
+                warn!("Hook \"{command}\" timed out after {}ms, killing it", timeout.as_millis());
+                //the group id is the pid of sh, a negative pid signals the whole group
+                unsafe { libc::kill(-(child.id() as i32), libc::SIGKILL) };
+                let _ = child.wait();
+                return
+            }
+            thread::sleep(WAIT_INTERVAL);
+        }
+    }
+}
+
+fn event_variables(event: &TrackEvent) -> Vec<(String, String)> {
+    vec![
+        ("NP_EVENT".to_string(), event.kind.name().to_string()),
+        ("NP_PLAYER".to_string(), event.player.clone()),
+        ("NP_ARTIST".to_string(), event.metadata.artist.trim().to_string()),
+        ("NP_TITLE".to_string(), event.metadata.title.clone()),
+        ("NP_ALBUM".to_string(), event.metadata.album.clone()),
+        ("NP_TIMESTAMP".to_string(), event.timestamp.to_string())
+    ]
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::{env, fs};
+    use crate::mpris_mediaplayer2::PlayerMetadata;
+
+    #[test]
+    fn timeout_kills_the_commands_started_by_the_hook() {
+        let pid_file = env::temp_dir().join(format!("nowplaying_hook_test_{}", std::process::id()));
+        let event = TrackEvent {
+            kind: TrackEventKind::Changed,
+            player: "vlc".to_string(),
+            timestamp: 0,
+            position_ms: 0,
+            metadata: PlayerMetadata::new()
+        };
+        let command = format!("sleep 30 & echo $! > {}; wait", pid_file.display());
+        let child = HookRunner::spawn_command(&command, &event).unwrap();
+        HookRunner::wait_for_command(child, command, time::Duration::from_millis(300));
+
+        let pid = fs::read_to_string(&pid_file).unwrap().trim().to_string();
+        let _ = fs::remove_file(&pid_file);
+        //killed, or a zombie nobody reaps in a container without init
+        thread::sleep(time::Duration::from_millis(100));
+        let running = match fs::read_to_string(format!("/proc/{pid}/stat")) {
+            Ok(stat) => !stat.rsplit(')').next().unwrap_or("").trim_start().starts_with('Z'),
+            Err(_error) => false
+        };
+        assert!(!running, "sleep {pid} survived the timeout");
+    }
+}
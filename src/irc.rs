@@ -0,0 +1,288 @@
+use std::collections::HashMap;
+use std::io;
+use std::io::{prelude::*, BufReader};
+use std::net::TcpStream;
+use std::sync::mpsc::{Receiver, RecvTimeoutError};
+use std::sync::{Arc, Mutex};
+use std::{thread, time};
+
+use log::warn;
+
+use crate::config::Config;
+use crate::mqtt;
+use crate::nowplaying::{SharedNowplayingData, TrackEvent, TrackEventKind};
+use crate::template;
+
+const RECONNECT_DELAY: time::Duration = time::Duration::from_secs(10);
+const CHECK_INTERVAL: time::Duration = time::Duration::from_secs(1);
+const CONNECT_TIMEOUT: time::Duration = time::Duration::from_secs(5);
+
+//IRC (e.g. Twitch chat) bot answering song commands and announcing track changes
+pub struct IrcBot {
+    address: String,
+    nick: String,
+    password: String,
+    channels: Vec<String>,
+    song_command: String,
+    song_template: String,
+    last_song_command: String,
+    last_song_template: String,
+    command_cooldown: time::Duration,
+    announce: bool,
+    announce_template: String,
+    announce_interval: time::Duration,
+    config: Config,
+    state: SharedNowplayingData
+}
+
+impl IrcBot {
+    pub fn new(config: &Config, state: SharedNowplayingData) -> IrcBot {
+        IrcBot {
+            address: format!("{}:{}", config.irc.server, config.irc.port),
+            nick: config.irc.nick.clone(),
+            password: config.irc.password.clone(),
+            channels: config.irc.channels.iter()
+                .map(|channel| if channel.starts_with('#') { channel.clone() } else { format!("#{channel}") })
+                .collect(),
+            song_command: config.irc.song_command.clone(),
+            song_template: config.irc.song_template.clone(),
+            last_song_command: config.irc.last_song_command.clone(),
+            last_song_template: config.irc.last_song_template.clone(),
+            command_cooldown: config.irc.command_cooldown,
+            announce: config.irc.announce,
+            announce_template: config.irc.announce_template.clone(),
+            announce_interval: config.irc.announce_interval,
+            config: config.clone(),
+            state
+        }
+    }
+
+    pub fn start(self, rx: Receiver<TrackEvent>) {
+        let bot = Arc::new(self);
+        //latest track change that still has to be announced
+        let mut pending_announcement: Option<TrackEvent> = None;
+        let mut last_announcement: Option<time::Instant> = None;
+
+        loop {
+            let (writer, reader) = match bot.connect() {
+                Ok(connection) => connection,
+                Err(error) => {
+                    warn!("Cannot connect to IRC server {}: {}", bot.address, error);
+                    match bot.wait_for_reconnect(&rx, &mut pending_announcement) {
+                        true => continue,
+                        false => return
+                    }
+                }
+            };
+            let reader_bot = Arc::clone(&bot);
+            let reader_writer = Arc::clone(&writer);
+            let reader = thread::spawn(move || reader_bot.handle_messages(reader, reader_writer));
+
+            while !reader.is_finished() {
+                match rx.recv_timeout(CHECK_INTERVAL) {
+                    Ok(event) => {
+                        if bot.announce && event.kind == TrackEventKind::Changed {
+                            pending_announcement = Some(event);
+                        }
+                    }
+                    Err(RecvTimeoutError::Timeout) => (),
+                    Err(RecvTimeoutError::Disconnected) => {
+                        //the reader would keep the session and answer with the old settings, next to a new bot with the same nick
+                        let _ = send_line(&writer, "QUIT");
+                        let _ = writer.lock().unwrap().shutdown(std::net::Shutdown::Both);
+                        let _ = reader.join();
+                        return
+                    }
+                }
+
+                //rate limit: at most one announcement per interval, older changes are dropped
+                let ready = last_announcement.map_or(true, |last| last.elapsed() >= bot.announce_interval);
+                if let (true, Some(event)) = (ready, &pending_announcement) {
+                    let text = template::render(&bot.announce_template, &bot.config, &event.player, &event.metadata, &bot.state.lock().unwrap().previous);
+                    if bot.send_to_channels(&writer, &text).is_err() {
+                        break;
+                    }
+                    pending_announcement = None;
+                    last_announcement = Some(time::Instant::now());
+                }
+            }
+            warn!("Lost connection to IRC server {}, reconnecting", bot.address);
+            let _ = writer.lock().unwrap().shutdown(std::net::Shutdown::Both);
+            let _ = reader.join();
+            if !bot.wait_for_reconnect(&rx, &mut pending_announcement) {
+                return
+            }
+        }
+    }
+
+    //waits RECONNECT_DELAY while still taking events, false once the output is stopped
+    fn wait_for_reconnect(&self, rx: &Receiver<TrackEvent>, pending_announcement: &mut Option<TrackEvent>) -> bool {
+        let deadline = time::Instant::now() + RECONNECT_DELAY;
+        loop {
+            match rx.recv_timeout(deadline.saturating_duration_since(time::Instant::now())) {
+                Ok(event) => {
+                    if self.announce && event.kind == TrackEventKind::Changed {
+                        *pending_announcement = Some(event);
+                    }
+                }
+                Err(RecvTimeoutError::Timeout) => return true,
+                Err(RecvTimeoutError::Disconnected) => return false
+            }
+        }
+    }
+
+    fn connect(&self) -> Result<(Arc<Mutex<TcpStream>>, BufReader<TcpStream>), io::Error> {
+        let stream = mqtt::connect_with_timeout(&self.address, CONNECT_TIMEOUT)?;
+        let reader = BufReader::new(stream.try_clone()?);
+        let writer = Arc::new(Mutex::new(stream));
+
+        if self.password != "" {
+            send_line(&writer, &format!("PASS {}", self.password))?;
+        }
+        send_line(&writer, &format!("NICK {}", self.nick))?;
+        send_line(&writer, &format!("USER {} 0 * :1Lt-NowPlaying", self.nick))?;
+        for channel in &self.channels {
+            send_line(&writer, &format!("JOIN {channel}"))?;
+        }
+        Ok((writer, reader))
+    }
+
+    //Answers PING and chat commands until the connection is closed
+    fn handle_messages(&self, reader: BufReader<TcpStream>, writer: Arc<Mutex<TcpStream>>) {
+        let mut last_answers: HashMap<String, time::Instant> = HashMap::new();
+        for line in reader.lines() {
+            let line = match line {
+                Ok(line) => line,
+                Err(_error) => return
+            };
+            if let Some(token) = line.strip_prefix("PING") {
+                if send_line(&writer, &format!("PONG{token}")).is_err() {
+                    return
+                }
+                continue;
+            }
+
+            //":nick!user@host PRIVMSG #channel :message"
+            let (channel, message) = match parse_privmsg(&line) {
+                Some(privmsg) => privmsg,
+                None => continue
+            };
+            let command = message.split_whitespace().next().unwrap_or_default().to_lowercase();
+            let answer = if command == self.song_command {
+                self.song_answer()
+            } else if command == self.last_song_command {
+                self.last_song_answer()
+            } else {
+                continue;
+            };
+
+            let key = format!("{channel} {command}");
+            if last_answers.get(&key).map_or(false, |last| last.elapsed() < self.command_cooldown) {
+                continue;
+            }
+            last_answers.insert(key, time::Instant::now());
+            if send_line(&writer, &format!("PRIVMSG {channel} :{answer}")).is_err() {
+                return
+            }
+        }
+    }
+
+    fn song_answer(&self) -> String {
+        let state = self.state.lock().unwrap().clone();
+        if state.status == TrackEventKind::Stopped.status() || state.metadata.title == "" {
+            return "Nothing is playing right now".to_string()
+        }
+        template::render(&self.song_template, &self.config, &state.player, &state.metadata, &state.previous)
+    }
+
+    fn last_song_answer(&self) -> String {
+        let state = self.state.lock().unwrap().clone();
+        if state.previous.title == "" {
+            return "No song was played before this one".to_string()
+        }
+        template::render(&self.last_song_template, &self.config, &state.player, &state.previous, &state.previous)
+    }
+
+    fn send_to_channels(&self, writer: &Arc<Mutex<TcpStream>>, text: &str) -> Result<(), io::Error> {
+        for channel in &self.channels {
+            send_line(writer, &format!("PRIVMSG {channel} :{text}"))?;
+        }
+        Ok(())
+    }
+}
+
+fn send_line(writer: &Arc<Mutex<TcpStream>>, line: &str) -> Result<(), io::Error> {
+    //IRC lines must not contain line breaks
+    let line = line.replace(['\r', '\n'], " ");
+    writer.lock().unwrap().write_all(format!("{line}\r\n").as_bytes())
+}
+
+//returns (channel, message) of a PRIVMSG line
+fn parse_privmsg(line: &str) -> Option<(String, String)> {
+    let line = line.trim_end_matches('\r');
+    let rest = match line.strip_prefix(':') {
+        Some(rest) => rest.split_once(' ')?.1,
+        None => line
+    };
+    let rest = rest.strip_prefix("PRIVMSG ")?;
+    let (channel, message) = rest.split_once(" :")?;
+    Some((channel.to_string(), message.to_string()))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::net::TcpListener;
+    use std::sync::mpsc;
+    use crate::nowplaying::NowplayingData;
+
+    fn bot(port: u16) -> IrcBot {
+        let mut config = Config::defaults();
+        config.irc.server = "127.0.0.1".to_string();
+        config.irc.port = port;
+        config.irc.nick = "nowplaying".to_string();
+        config.irc.channels = vec!["stream".to_string()];
+        IrcBot::new(&config, Arc::new(Mutex::new(NowplayingData::new())))
+    }
+
+    //waits up to a deadline, a bot that does not stop fails the test instead of hanging it
+    fn stopped(output: &thread::JoinHandle<()>) -> bool {
+        let deadline = time::Instant::now() + time::Duration::from_secs(3);
+        while !output.is_finished() && time::Instant::now() < deadline {
+            thread::sleep(time::Duration::from_millis(20));
+        }
+        output.is_finished()
+    }
+
+    #[test]
+    fn leaves_the_server_when_stopped() {
+        let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+        let port = listener.local_addr().unwrap().port();
+        let server = thread::spawn(move || {
+            let (stream, _address) = listener.accept().unwrap();
+            stream.set_read_timeout(Some(time::Duration::from_secs(3))).unwrap();
+            //ends when the bot closes the connection, a timeout is an error
+            BufReader::new(stream).lines().collect::<Result<Vec<String>, io::Error>>()
+        });
+        let (tx, rx) = mpsc::channel();
+        let output = thread::spawn(move || bot(port).start(rx));
+        thread::sleep(time::Duration::from_millis(300));
+        drop(tx);
+
+        assert!(stopped(&output));
+        let lines = server.join().unwrap().expect("the bot kept the connection open");
+        assert!(lines.contains(&"NICK nowplaying".to_string()));
+        assert!(lines.contains(&"JOIN #stream".to_string()));
+        assert_eq!(lines.last().map(|line| line.as_str()), Some("QUIT"));
+    }
+
+    #[test]
+    fn stops_while_the_server_is_unreachable() {
+        let port = TcpListener::bind("127.0.0.1:0").unwrap().local_addr().unwrap().port();
+        let (tx, rx) = mpsc::channel();
+        let output = thread::spawn(move || bot(port).start(rx));
+        thread::sleep(time::Duration::from_millis(300));
+        drop(tx);
+        assert!(stopped(&output));
+    }
+}
@@ -0,0 +1,195 @@
+use std::env;
+use std::fs;
+use std::fs::{File, OpenOptions};
+use std::io::prelude::*;
+use std::path::PathBuf;
+use std::sync::Mutex;
+
+use chrono::Local;
+use log::{Level, LevelFilter, Log, Metadata, Record};
+
+use crate::cli::Verbosity;
+use crate::config::Config;
+
+const LOG_FILE_NAME: &str = "nowplaying.log";
+const CRATE_NAME: &str = env!("CARGO_CRATE_NAME");
+
+//Logger behind the log macros: stderr (plain or journald) and an optional rotating log file
+pub struct Logger {
+    level: LevelFilter,
+    //module name (without the crate prefix) and its level, e.g. web_display=debug
+    modules: Vec<(String, LevelFilter)>,
+    timestamps: bool,
+    journald: bool,
+    file: Option<Mutex<LogFile>>
+}
+
+struct LogFile {
+    path: PathBuf,
+    file: File,
+    size: u64,
+    max_size: u64,
+    max_files: u32
+}
+
+//--quiet only shows warnings and errors, --verbose at least debug output
+pub fn init(config: &Config, verbosity: Verbosity) {
+    let mut level = parse_level(&config.log_level);
+    match verbosity {
+        Verbosity::Quiet => level = level.min(LevelFilter::Warn),
+        Verbosity::Verbose => level = level.max(LevelFilter::Debug),
+        Verbosity::Normal => ()
+    }
+    let modules: Vec<(String, LevelFilter)> = config.log_modules.split(',')
+        .filter_map(|module| module.split_once('='))
+        .map(|(name, module_level)| (name.trim().to_string(), parse_level(module_level)))
+        .collect();
+    let journald = match config.log_format.as_str() {
+        "journald" => true,
+        "text" => false,
+        //auto: systemd sets JOURNAL_STREAM when stderr is connected to the journal
+        _ => env::var_os("JOURNAL_STREAM").is_some()
+    };
+    let file = match config.log_file {
+        true => LogFile::open(config.work_directory().join(LOG_FILE_NAME), config.log_file_max_size_kb * 1024, config.log_file_max_files),
+        false => None
+    };
+
+    let max_level = modules.iter().map(|(_name, module_level)| *module_level).fold(level, Ord::max);
+    let logger = Logger {
+        level,
+        modules,
+        timestamps: config.log_timestamps,
+        journald,
+        file: file.map(Mutex::new)
+    };
+    if log::set_boxed_logger(Box::new(logger)).is_ok() {
+        log::set_max_level(max_level);
+    }
+}
+
+//the levels are validated when the config is read
+fn parse_level(level: &str) -> LevelFilter {
+    level.trim().parse::<LevelFilter>().unwrap_or(LevelFilter::Info)
+}
+
+impl Logger {
+    fn module_level(&self, target: &str) -> LevelFilter {
+        let module = module_name(target);
+        //the most specific filter wins
+        let filter = self.modules.iter()
+            .filter(|(name, _level)| module == name || module.starts_with(&format!("{name}::")))
+            .max_by_key(|(name, _level)| name.len());
+        match filter {
+            Some((_name, level)) => *level,
+            //libraries like ureq are only heard when something goes wrong
+            None if !is_own_module(target) => self.level.min(LevelFilter::Warn),
+            None => self.level
+        }
+    }
+}
+
+impl Log for Logger {
+    fn enabled(&self, metadata: &Metadata) -> bool {
+        metadata.level() <= self.module_level(metadata.target())
+    }
+
+    fn log(&self, record: &Record) {
+        if !self.enabled(record.metadata()) {
+            return
+        }
+        let module = module_name(record.target());
+        let timestamp = Local::now().format("%Y-%m-%d %H:%M:%S%.3f");
+
+        //journald adds its own timestamps and reads the priority from the <n> prefix
+        let line = match (self.journald, self.timestamps) {
+            (true, _) => format!("<{}>{}: {}", syslog_priority(record.level()), module, record.args()),
+            (false, true) => format!("{} {:<5} {}: {}", timestamp, record.level(), module, record.args()),
+            (false, false) => format!("{:<5} {}: {}", record.level(), module, record.args())
+        };
+        eprintln!("{line}");
+
+        if let Some(file) = &self.file {
+            let line = format!("{} {:<5} {}: {}\n", timestamp, record.level(), module, record.args());
+            file.lock().unwrap().write(&line);
+        }
+    }
+
+    fn flush(&self) {
+        if let Some(file) = &self.file {
+            let _ = file.lock().unwrap().file.flush();
+        }
+    }
+}
+
+impl LogFile {
+    fn open(path: PathBuf, max_size: u64, max_files: u32) -> Option<LogFile> {
+        //the logger starts before the work directory is created
+        if let Some(directory) = path.parent() {
+            let _ = fs::create_dir_all(directory);
+        }
+        match OpenOptions::new().create(true).append(true).open(&path) {
+            Ok(file) => {
+                let size = file.metadata().map(|metadata| metadata.len()).unwrap_or(0);
+                Some(LogFile { path, file, size, max_size, max_files })
+            }
+            Err(error) => {
+                eprintln!("Cannot open log file {}: {}", path.display(), error);
+                None
+            }
+        }
+    }
+
+    fn write(&mut self, line: &str) {
+        if self.max_size > 0 && self.size + line.len() as u64 > self.max_size {
+            self.rotate();
+        }
+        if self.file.write_all(line.as_bytes()).is_ok() {
+            self.size += line.len() as u64;
+        }
+    }
+
+    //nowplaying.log -> nowplaying.log.1 -> ... -> nowplaying.log.<max_files>, like the play history
+    fn rotate(&mut self) {
+        for index in (1..self.max_files).rev() {
+            let from = self.rotated_path(index);
+            if from.exists() {
+                let _ = fs::rename(&from, self.rotated_path(index + 1));
+            }
+        }
+        let _ = match self.max_files {
+            0 => fs::remove_file(&self.path),
+            _ => fs::rename(&self.path, self.rotated_path(1))
+        };
+        if let Ok(file) = File::create(&self.path) {
+            self.file = file;
+            self.size = 0;
+        }
+    }
+
+    fn rotated_path(&self, index: u32) -> PathBuf {
+        PathBuf::from(format!("{}.{}", self.path.display(), index))
+    }
+}
+
+//nowplaying_1lt::web_display -> web_display, other crates keep their name
+fn module_name(target: &str) -> &str {
+    match target.strip_prefix(CRATE_NAME) {
+        Some(module) if module == "" => "main",
+        Some(module) if module.starts_with("::") => &module[2..],
+        _ => target
+    }
+}
+
+fn is_own_module(target: &str) -> bool {
+    target == CRATE_NAME || target.starts_with(&format!("{CRATE_NAME}::"))
+}
+
+fn syslog_priority(level: Level) -> u8 {
+    match level {
+        Level::Error => 3,
+        Level::Warn => 4,
+        Level::Info => 6,
+        Level::Debug | Level::Trace => 7
+    }
+}
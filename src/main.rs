@@ -2,27 +2,102 @@ mod mpris_mediaplayer2;
 mod environment;
 mod nowplaying;
 mod config;
+mod config_format;
+mod config_migration;
+mod config_reader;
 mod web_display;
+mod history;
+mod template;
+mod setlist;
+mod stats;
+mod hooks;
+mod webhooks;
+mod mqtt;
+mod obs;
+mod cover_art;
+mod discord;
+mod irc;
+mod scrobbler;
+mod notifications;
+mod dbus_service;
+mod control_socket;
+mod bar;
+mod cli;
+mod logger;
+mod paths;
+
+use std::env;
+use std::process;
 
 use environment::WorkingEnvironment;
 
 fn main() {
+    let (options, args) = match cli::GlobalOptions::from_args(env::args().skip(1).collect()) {
+        Ok(parsed) => parsed,
+        Err(error) => cli::usage_error(&error)
+    };
+    if let Some(data_directory) = &options.data_directory {
+        paths::set_data_directory(data_directory);
+    }
+    match args.first().map(|arg| arg.as_str()) {
+        None | Some("run") => (),
+        Some("--bar") => run_bar(&options, &args[1..]),
+        Some("status") => cli::status(&options, &args[1..]),
+        Some("control") => cli::control(&options, &args[1..]),
+        Some("list-players") => cli::list_players(&options, &args[1..]),
+        Some("history") => cli::history(&options, &args[1..]),
+        Some("config") => cli::config_command(&options, &args[1..]),
+        Some("setlist") => cli::export_setlist(&options, &args[1..]),
+        Some("stats") => cli::print_stats(&options, &args[1..]),
+        Some("help") | Some("--help") | Some("-h") => {
+            println!("{}", cli::USAGE);
+            process::exit(cli::EXIT_OK);
+        }
+        Some("version") | Some("--version") | Some("-V") => {
+            println!("1Lt-NowPlaying {}", env!("CARGO_PKG_VERSION"));
+            process::exit(cli::EXIT_OK);
+        }
+        Some(command) => cli::usage_error(&format!("unknown command: {command}"))
+    }
+    if args.len() > 1 {
+        cli::usage_error(&format!("unknown option: {}", args[1]));
+    }
+
     let version = env!("CARGO_PKG_VERSION");
-    print_agpl_v3_disclaimer(version);
-    print_external_librarys();
+    if options.verbosity != cli::Verbosity::Quiet {
+        print_agpl_v3_disclaimer(version);
+        print_external_librarys();
+    }
 
-    let config = config::Config::new();
+    let config = options.load_service_config();
+    logger::init(&config, options.verbosity);
+    log::debug!("config file: {}", options.config_path());
+    log::debug!("work directory: {}", config.work_directory().display());
     
     let work_env = WorkingEnvironment::new(config.clone());
 
-    let nowplaying = nowplaying::Service::new(&config, work_env);
+    let nowplaying = nowplaying::Service::new(&config, work_env, options.config_path(), options.profile.clone());
     nowplaying.start();
 
 }
 
-
-
-
+fn run_bar(options: &cli::GlobalOptions, args: &[String]) {
+    let format = match bar::BarFormat::from_args(args) {
+        Ok(format) => format,
+        Err(error) => {
+            eprintln!("{error}");
+            process::exit(cli::EXIT_USAGE);
+        }
+    };
+    let config = options.load_config();
+    //stdout belongs to the bar, track changes are not logged unless --verbose
+    let verbosity = match options.verbosity {
+        cli::Verbosity::Verbose => cli::Verbosity::Verbose,
+        _ => cli::Verbosity::Quiet
+    };
+    logger::init(&config, verbosity);
+    nowplaying::Service::new_bar(&config, format).start();
+}
 
 fn print_agpl_v3_disclaimer(version: &str) {
     println!("1Lt-NowPlaying {} (AGPL 3.0)", version);
@@ -43,5 +118,18 @@ fn print_agpl_v3_disclaimer(version: &str) {
 fn print_external_librarys() {
     println!("This software uses external libraries:");
     println!("dbus-rs v0.9.5 (Apache-2.0/MIT)");
+    println!("dbus-crossroads v0.5 (Apache-2.0/MIT)");
+    println!("serde v1 (Apache-2.0/MIT)");
+    println!("serde_json v1 (Apache-2.0/MIT)");
+    println!("chrono v0.4 (Apache-2.0/MIT)");
+    println!("ureq v2 (Apache-2.0/MIT)");
+    println!("tungstenite v0.24 (Apache-2.0/MIT)");
+    println!("sha2 v0.10 (Apache-2.0/MIT)");
+    println!("base64 v0.22 (Apache-2.0/MIT)");
+    println!("md-5 v0.10 (Apache-2.0/MIT)");
+    println!("log v0.4 (Apache-2.0/MIT)");
+    println!("signal-hook v0.3 (Apache-2.0/MIT)");
+    println!("libc v0.2 (Apache-2.0/MIT)");
+    println!("toml v0.8 (Apache-2.0/MIT)");
     println!("\n");
 }
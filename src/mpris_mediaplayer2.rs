@@ -1,12 +1,26 @@
 use dbus::{blocking::Connection, arg};
-use std::collections::HashMap;
+use serde::{Deserialize, Serialize};
+use std::collections::{BTreeMap, HashMap};
 use std::time::Duration;
 
-#[derive(PartialEq)]
+#[derive(Clone, Serialize, Deserialize)]
 pub struct PlayerMetadata {
     pub artist: String,
     pub title: String,
     pub album: String,
+    //track length in milliseconds, 0 if the player does not report it
+    #[serde(default)]
+    pub length_ms: u64,
+    //every metadata key reported by the player, values converted to text
+    #[serde(default)]
+    pub fields: BTreeMap<String, String>,
+}
+
+//the same track, players fill in length and artUrl later without starting a new one
+impl PartialEq for PlayerMetadata {
+    fn eq(&self, other: &PlayerMetadata) -> bool {
+        self.artist == other.artist && self.title == other.title && self.album == other.album
+    }
 }
 
 impl PlayerMetadata {
@@ -15,6 +29,8 @@ impl PlayerMetadata {
             artist: String::new(),
             title: String::new(),
             album: String::new(),
+            length_ms: 0,
+            fields: BTreeMap::new(),
         }
     }
     pub fn update_metadata_of_player(mut self, mediaplayer: String) -> Result<PlayerMetadata, Box<dyn std::error::Error>> {
@@ -57,10 +73,66 @@ impl PlayerMetadata {
             self.album = format!("{}", album);
         }
 
+        //Get Length (microseconds)
+        if metadata.contains_key("mpris:length") {
+            let length = &metadata["mpris:length"].0;
+            let length = length.as_i64().or(length.as_u64().map(|length| length as i64)).unwrap_or(0);
+            self.length_ms = length.max(0) as u64 / 1000;
+        }
+
+        //Keep all other metadata as well
+        for (key, value) in &metadata {
+            self.fields.insert(key.clone(), refarg_to_string(&value.0));
+        }
+
         Ok(self)
     }
 }
 
+fn refarg_to_string(value: &dyn arg::RefArg) -> String {
+    if let Some(text) = value.as_str() {
+        return text.to_string()
+    }
+    if let Some(number) = value.as_i64() {
+        return number.to_string()
+    }
+    if let Some(number) = value.as_u64() {
+        return number.to_string()
+    }
+    if let Some(number) = value.as_f64() {
+        return number.to_string()
+    }
+    match value.as_iter() {
+        Some(values) => values.map(|v| refarg_to_string(v)).collect::<Vec<String>>().join(", "),
+        None => String::new()
+    }
+}
+
+//a metadata value as the player sends it: D-Bus type signature and the value as text
+#[derive(Clone, Serialize)]
+pub struct RawMetadataValue {
+    pub signature: String,
+    pub value: String,
+}
+
+//all metadata keys of a player without any interpretation, for diagnostics
+pub fn get_media_player_raw_metadata(mediaplayer: &String) -> Result<BTreeMap<String, RawMetadataValue>, Box<dyn std::error::Error>> {
+    let session = Connection::new_session()?;
+    let player_name = format!("org.mpris.MediaPlayer2.{}", mediaplayer);
+    let proxy = session.with_proxy(player_name, "/org/mpris/MediaPlayer2", Duration::from_millis(5000));
+
+    use dbus::blocking::stdintf::org_freedesktop_dbus::Properties;
+    let metadata: HashMap<String, arg::Variant<Box<dyn arg::RefArg>>> = proxy.get("org.mpris.MediaPlayer2.Player", "Metadata")?;
+    let mut raw_metadata = BTreeMap::new();
+    for (key, value) in &metadata {
+        raw_metadata.insert(key.clone(), RawMetadataValue {
+            signature: value.0.signature().to_string(),
+            value: refarg_to_string(&value.0),
+        });
+    }
+    Ok(raw_metadata)
+}
+
 pub fn get_media_players() -> Result<Vec<String>, Box<dyn std::error::Error>> {
     //Connect to D-Bus and set operating location
     let session = Connection::new_session()?;
@@ -80,6 +152,18 @@ pub fn get_media_players() -> Result<Vec<String>, Box<dyn std::error::Error>> {
 }
 
 pub fn get_media_player_playback_status(mediaplayer: &String) -> Result<bool, Box<dyn std::error::Error>> {
+    let playback_status = get_media_player_playback_status_text(mediaplayer)?;
+
+    //println!("{}", playback_status);
+    if playback_status == "Playing"{
+        Ok(true)
+    } else {
+        Ok(false)
+    }
+}
+
+//"Playing", "Paused" or "Stopped"
+pub fn get_media_player_playback_status_text(mediaplayer: &String) -> Result<String, Box<dyn std::error::Error>> {
     let session = Connection::new_session()?;
     let player_name = format!("org.mpris.MediaPlayer2.{}", mediaplayer);
     let proxy = session.with_proxy(player_name, "/org/mpris/MediaPlayer2", Duration::from_millis(5000));
@@ -87,11 +171,46 @@ pub fn get_media_player_playback_status(mediaplayer: &String) -> Result<bool, Bo
     //get mediaplayer playback status
     use dbus::blocking::stdintf::org_freedesktop_dbus::Properties;
     let playback_status: String = proxy.get("org.mpris.MediaPlayer2.Player", "PlaybackStatus")?;
+    Ok(playback_status)
+}
 
-    //println!("{}", playback_status);
-    if playback_status == "Playing"{
-        Ok(true)
-    } else {
-        Ok(false)
-    }
+//human readable player name, e.g. "Spotify"
+pub fn get_media_player_identity(mediaplayer: &String) -> Result<String, Box<dyn std::error::Error>> {
+    let session = Connection::new_session()?;
+    let player_name = format!("org.mpris.MediaPlayer2.{}", mediaplayer);
+    let proxy = session.with_proxy(player_name, "/org/mpris/MediaPlayer2", Duration::from_millis(5000));
+
+    use dbus::blocking::stdintf::org_freedesktop_dbus::Properties;
+    let identity: String = proxy.get("org.mpris.MediaPlayer2", "Identity")?;
+    Ok(identity)
+}
+
+//Maps a control action (play, pause, play_pause, next, previous, stop) onto the MPRIS player method
+pub fn control_media_player(mediaplayer: &String, action: &str) -> Result<(), Box<dyn std::error::Error>> {
+    let method = match action {
+        "play" => "Play",
+        "pause" => "Pause",
+        "play_pause" | "toggle" => "PlayPause",
+        "next" => "Next",
+        "previous" | "prev" => "Previous",
+        "stop" => "Stop",
+        _ => return Err(format!("unknown control action: {action}").into())
+    };
+
+    let session = Connection::new_session()?;
+    let player_name = format!("org.mpris.MediaPlayer2.{}", mediaplayer);
+    let proxy = session.with_proxy(player_name, "/org/mpris/MediaPlayer2", Duration::from_millis(5000));
+    proxy.method_call::<(), _, _, _>("org.mpris.MediaPlayer2.Player", method, ())?;
+    Ok(())
+}
+
+//playback position in microseconds
+pub fn get_media_player_position(mediaplayer: &String) -> Result<i64, Box<dyn std::error::Error>> {
+    let session = Connection::new_session()?;
+    let player_name = format!("org.mpris.MediaPlayer2.{}", mediaplayer);
+    let proxy = session.with_proxy(player_name, "/org/mpris/MediaPlayer2", Duration::from_millis(5000));
+
+    use dbus::blocking::stdintf::org_freedesktop_dbus::Properties;
+    let position: i64 = proxy.get("org.mpris.MediaPlayer2.Player", "Position")?;
+    Ok(position)
 }
@@ -0,0 +1,346 @@
+use std::io;
+use std::io::prelude::*;
+use std::net::{Shutdown, TcpStream, ToSocketAddrs};
+use std::sync::mpsc::{Receiver, RecvTimeoutError};
+use std::sync::{Arc, Mutex};
+use std::{thread, time};
+
+use log::warn;
+
+use crate::config::Config;
+use crate::mpris_mediaplayer2;
+use crate::nowplaying::TrackEvent;
+
+const RECONNECT_DELAY: time::Duration = time::Duration::from_millis(5000);
+//connecting and waiting for CONNACK, a broker that does not answer must not block the thread
+const CONNECT_TIMEOUT: time::Duration = time::Duration::from_millis(5000);
+
+//Minimal MQTT 3.1.1 client (QoS 0 only) publishing the retained now playing state
+pub struct MqttPublisher {
+    address: String,
+    client_id: String,
+    username: String,
+    password: String,
+    topic_prefix: String,
+    commands: bool,
+    keep_alive: time::Duration,
+    //player of the last event, target for control commands
+    current_player: Arc<Mutex<Option<String>>>
+}
+
+impl MqttPublisher {
+    pub fn new(config: &Config) -> MqttPublisher {
+        MqttPublisher {
+            address: format!("{}:{}", config.mqtt.host, config.mqtt.port),
+            client_id: config.mqtt.client_id.clone(),
+            username: config.mqtt.username.clone(),
+            password: config.mqtt.password.clone(),
+            topic_prefix: config.mqtt.topic_prefix.trim_end_matches('/').to_string(),
+            commands: config.mqtt.commands,
+            keep_alive: config.mqtt.keep_alive,
+            current_player: Arc::new(Mutex::new(None))
+        }
+    }
+
+    pub fn start(self, rx: Receiver<TrackEvent>) {
+        let mut connection: Option<TcpStream> = None;
+        let mut last_event: Option<TrackEvent> = None;
+        let mut last_connect_attempt: Option<time::Instant> = None;
+
+        let ping_interval = (self.keep_alive / 2).max(time::Duration::from_secs(1));
+        loop {
+            let event = match rx.recv_timeout(ping_interval) {
+                Ok(event) => Some(event),
+                Err(RecvTimeoutError::Timeout) => None,
+                Err(RecvTimeoutError::Disconnected) => {
+                    if let Some(stream) = &connection {
+                        disconnect(stream);
+                    }
+                    return
+                }
+            };
+            if let Some(event) = &event {
+                *self.current_player.lock().unwrap() = Some(event.player.clone());
+                last_event = Some(event.clone());
+            }
+
+            if connection.is_none() && last_connect_attempt.map_or(true, |attempt| attempt.elapsed() >= RECONNECT_DELAY) {
+                last_connect_attempt = Some(time::Instant::now());
+                connection = match self.connect() {
+                    Ok(stream) => {
+                        //publish the last known state again after a reconnect
+                        if event.is_none() {
+                            if let Some(last_event) = &last_event {
+                                let _ = self.publish_state(&stream, last_event);
+                            }
+                        }
+                        Some(stream)
+                    }
+                    Err(error) => {
+                        warn!("Cannot connect to MQTT broker {}: {}", self.address, error);
+                        None
+                    }
+                };
+            }
+
+            let stream = match &connection {
+                Some(stream) => stream,
+                None => continue
+            };
+            let result = match &event {
+                Some(event) => self.publish_state(stream, event),
+                None => write_packet(stream, 0xC0, &[])
+            };
+            if let Err(error) = result {
+                warn!("Lost connection to MQTT broker {}: {}", self.address, error);
+                //also ends the command reader
+                let _ = stream.shutdown(Shutdown::Both);
+                connection = None;
+            }
+        }
+    }
+
+    fn connect(&self) -> Result<TcpStream, io::Error> {
+        let mut stream = connect_with_timeout(&self.address, CONNECT_TIMEOUT)?;
+        stream.set_write_timeout(Some(CONNECT_TIMEOUT))?;
+        stream.set_read_timeout(Some(CONNECT_TIMEOUT))?;
+
+        let mut flags = 0x02; //clean session
+        let mut payload = encode_string(&self.client_id)?;
+        if self.username != "" {
+            flags |= 0x80;
+            payload.extend(encode_string(&self.username)?);
+        }
+        if self.password != "" {
+            flags |= 0x40;
+            payload.extend(encode_string(&self.password)?);
+        }
+        let mut body = encode_string("MQTT")?;
+        body.push(4); //protocol level 3.1.1
+        body.push(flags);
+        body.extend((self.keep_alive.as_secs() as u16).to_be_bytes());
+        body.extend(payload);
+        write_packet(&stream, 0x10, &body)?;
+
+        let (packet_type, connack) = match read_packet(&mut stream) {
+            Ok(packet) => packet,
+            Err(error) if matches!(error.kind(), io::ErrorKind::WouldBlock | io::ErrorKind::TimedOut) => {
+                return Err(io::Error::new(io::ErrorKind::TimedOut, "broker did not answer with CONNACK in time"))
+            }
+            Err(error) => return Err(error)
+        };
+        if packet_type >> 4 != 2 || connack.len() < 2 {
+            return Err(io::Error::new(io::ErrorKind::InvalidData, "broker did not answer with CONNACK"))
+        }
+        if connack[1] != 0 {
+            return Err(io::Error::new(io::ErrorKind::PermissionDenied, format!("broker refused connection (code {})", connack[1])))
+        }
+        //the command reader waits for messages without a limit
+        stream.set_read_timeout(None)?;
+
+        if self.commands {
+            let mut subscribe = 1u16.to_be_bytes().to_vec();
+            subscribe.extend(encode_string(&format!("{}/command", self.topic_prefix))?);
+            subscribe.push(0); //QoS 0
+            write_packet(&stream, 0x82, &subscribe)?;
+        }
+
+        //also drains PINGRESP and SUBACK packets if commands are disabled
+        let reader = stream.try_clone()?;
+        let current_player = Arc::clone(&self.current_player);
+        thread::spawn(move || MqttPublisher::handle_commands(reader, current_player));
+        Ok(stream)
+    }
+
+    fn publish_state(&self, stream: &TcpStream, event: &TrackEvent) -> Result<(), io::Error> {
+        let status = event.kind.status();
+        let state = serde_json::json!({
+            "status": status,
+            "player": event.player,
+            "artist": event.metadata.artist.trim(),
+            "title": event.metadata.title,
+            "album": event.metadata.album,
+            "timestamp": event.timestamp
+        });
+
+        self.publish(stream, "state", &state.to_string())?;
+        self.publish(stream, "status", status)?;
+        self.publish(stream, "player", &event.player)?;
+        self.publish(stream, "artist", event.metadata.artist.trim())?;
+        self.publish(stream, "title", &event.metadata.title)?;
+        self.publish(stream, "album", &event.metadata.album)
+    }
+
+    //publishes a retained message to <topic_prefix>/<topic>
+    fn publish(&self, stream: &TcpStream, topic: &str, payload: &str) -> Result<(), io::Error> {
+        let mut body = encode_string(&format!("{}/{}", self.topic_prefix, topic))?;
+        body.extend(payload.as_bytes());
+        write_packet(stream, 0x31, &body)
+    }
+
+    //Reads incoming packets and maps messages on the command topic onto MPRIS methods
+    fn handle_commands(mut stream: TcpStream, current_player: Arc<Mutex<Option<String>>>) {
+        loop {
+            let (packet_type, body) = match read_packet(&mut stream) {
+                Ok(packet) => packet,
+                Err(_error) => return //the publisher notices the broken connection and reconnects
+            };
+            if packet_type >> 4 != 3 || body.len() < 2 {
+                continue;
+            }
+            let topic_length = u16::from_be_bytes([body[0], body[1]]) as usize;
+            let qos = (packet_type >> 1) & 0x03;
+            //skip the packet identifier for QoS > 0
+            let payload_start = 2 + topic_length + if qos > 0 { 2 } else { 0 };
+            let action = match body.get(payload_start..) {
+                Some(payload) => String::from_utf8_lossy(payload).trim().to_lowercase(),
+                None => continue
+            };
+
+            let player = match current_player.lock().unwrap().clone() {
+                Some(player) => player,
+                None => match mpris_mediaplayer2::get_media_players() {
+                    Ok(players) if !players.is_empty() => players[0].clone(),
+                    _ => {
+                        warn!("MQTT command \"{action}\" ignored: no mediaplayer found");
+                        continue;
+                    }
+                }
+            };
+            if let Err(error) = mpris_mediaplayer2::control_media_player(&player, &action) {
+                warn!("MQTT command \"{action}\" failed: {error}");
+            }
+        }
+    }
+}
+
+//every address of the host is tried, each with the timeout, also used by the IRC bot
+pub fn connect_with_timeout(address: &str, timeout: time::Duration) -> Result<TcpStream, io::Error> {
+    let mut last_error = io::Error::new(io::ErrorKind::NotFound, format!("{address} has no address"));
+    for socket_address in address.to_socket_addrs()? {
+        match TcpStream::connect_timeout(&socket_address, timeout) {
+            Ok(stream) => return Ok(stream),
+            Err(error) => last_error = error
+        }
+    }
+    Err(last_error)
+}
+
+//DISCONNECT tells the broker to drop the session right away, the shutdown ends the command reader
+fn disconnect(stream: &TcpStream) {
+    let _ = write_packet(stream, 0xE0, &[]);
+    let _ = stream.shutdown(Shutdown::Both);
+}
+
+//strings have a 16 bit length in MQTT
+fn encode_string(value: &str) -> Result<Vec<u8>, io::Error> {
+    let length = match u16::try_from(value.len()) {
+        Ok(length) => length,
+        Err(_error) => return Err(io::Error::new(io::ErrorKind::InvalidInput, format!("{} bytes are too long for an MQTT string", value.len())))
+    };
+    let mut encoded = length.to_be_bytes().to_vec();
+    encoded.extend(value.as_bytes());
+    Ok(encoded)
+}
+
+fn write_packet(mut stream: &TcpStream, header: u8, body: &[u8]) -> Result<(), io::Error> {
+    let mut packet = vec![header];
+    //remaining length as variable byte integer
+    let mut length = body.len();
+    loop {
+        let mut byte = (length % 128) as u8;
+        length /= 128;
+        if length > 0 {
+            byte |= 0x80;
+        }
+        packet.push(byte);
+        if length == 0 {
+            break;
+        }
+    }
+    packet.extend(body);
+    stream.write_all(&packet)
+}
+
+fn read_packet(stream: &mut TcpStream) -> Result<(u8, Vec<u8>), io::Error> {
+    let mut byte = [0u8; 1];
+    stream.read_exact(&mut byte)?;
+    let header = byte[0];
+
+    let mut length = 0usize;
+    let mut multiplier = 1usize;
+    loop {
+        stream.read_exact(&mut byte)?;
+        length += (byte[0] & 0x7F) as usize * multiplier;
+        if byte[0] & 0x80 == 0 {
+            break;
+        }
+        multiplier *= 128;
+        if multiplier > 128 * 128 * 128 {
+            return Err(io::Error::new(io::ErrorKind::InvalidData, "malformed MQTT packet length"))
+        }
+    }
+
+    let mut body = vec![0u8; length];
+    stream.read_exact(&mut body)?;
+    Ok((header, body))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::net::TcpListener;
+    use std::sync::mpsc;
+    use crate::mpris_mediaplayer2::PlayerMetadata;
+    use crate::nowplaying::TrackEventKind;
+
+    //accepts one client with CONNACK and returns the packet types it sent until the connection closed
+    fn broker() -> (u16, thread::JoinHandle<Vec<u8>>) {
+        let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+        let port = listener.local_addr().unwrap().port();
+        let handle = thread::spawn(move || {
+            let (mut stream, _address) = listener.accept().unwrap();
+            //a client that never closes fails the test instead of hanging it
+            stream.set_read_timeout(Some(time::Duration::from_secs(3))).unwrap();
+            let mut packets = Vec::new();
+            while let Ok((header, _body)) = read_packet(&mut stream) {
+                if header == 0x10 {
+                    write_packet(&stream, 0x20, &[0, 0]).unwrap();
+                }
+                packets.push(header);
+            }
+            packets
+        });
+        (port, handle)
+    }
+
+    #[test]
+    fn disconnects_when_the_output_stops() {
+        let (port, broker) = broker();
+        let mut config = Config::defaults();
+        config.mqtt.host = "127.0.0.1".to_string();
+        config.mqtt.port = port;
+        let publisher = MqttPublisher::new(&config);
+        let (tx, rx) = mpsc::channel();
+        let output = thread::spawn(move || publisher.start(rx));
+
+        let mut metadata = PlayerMetadata::new();
+        metadata.title = "Song".to_string();
+        tx.send(TrackEvent { kind: TrackEventKind::Changed, player: "vlc".to_string(), timestamp: 1, position_ms: 0, metadata }).unwrap();
+        thread::sleep(time::Duration::from_millis(300));
+        drop(tx);
+        output.join().unwrap();
+
+        let packets = broker.join().unwrap();
+        assert_eq!(packets.first(), Some(&0x10));
+        assert_eq!(packets.iter().filter(|header| **header == 0x31).count(), 6);
+        assert_eq!(packets.last(), Some(&0xE0));
+    }
+
+    #[test]
+    fn long_strings_are_rejected() {
+        assert_eq!(encode_string("ab").unwrap(), vec![0, 2, b'a', b'b']);
+        assert!(encode_string(&"a".repeat(65535)).is_ok());
+        assert!(encode_string(&"a".repeat(65536)).is_err());
+    }
+}
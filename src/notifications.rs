@@ -0,0 +1,82 @@
+use std::collections::HashMap;
+use std::path::PathBuf;
+use std::sync::mpsc::Receiver;
+use std::time::Duration;
+
+use dbus::{arg, blocking::Connection};
+use log::warn;
+
+use crate::config::Config;
+use crate::cover_art;
+use crate::mpris_mediaplayer2::PlayerMetadata;
+use crate::nowplaying::{TrackEvent, TrackEventKind};
+use crate::paths;
+use crate::template;
+
+//Shows a desktop notification (org.freedesktop.Notifications) on every track change
+pub struct DesktopNotifications {
+    summary: String,
+    body: String,
+    timeout_ms: i32,
+    show_cover: bool,
+    config: Config,
+    cover_directory: PathBuf,
+    //id of the last notification, replaced by the next one
+    replaces_id: u32
+}
+
+impl DesktopNotifications {
+    pub fn new(config: &Config) -> DesktopNotifications {
+        DesktopNotifications {
+            summary: config.notifications.summary.clone(),
+            //allow line breaks in the config value
+            body: config.notifications.body.replace("\\n", "\n"),
+            timeout_ms: config.notifications.timeout_ms,
+            show_cover: config.notifications.show_cover,
+            config: config.clone(),
+            cover_directory: paths::cache_directory(),
+            replaces_id: 0
+        }
+    }
+
+    pub fn start(mut self, rx: Receiver<TrackEvent>) {
+        let mut previous = PlayerMetadata::new();
+        for event in rx {
+            if event.kind != TrackEventKind::Changed {
+                continue;
+            }
+            if let Err(error) = self.notify(&event, &previous) {
+                warn!("Cannot show desktop notification: {}", error);
+            }
+            previous = event.metadata;
+        }
+    }
+
+    fn notify(&mut self, event: &TrackEvent, previous: &PlayerMetadata) -> Result<(), Box<dyn std::error::Error>> {
+        let summary = template::render(&self.summary, &self.config, &event.player, &event.metadata, previous);
+        let body = template::render(&self.body, &self.config, &event.player, &event.metadata, previous);
+        let cover = match self.show_cover {
+            true => cover_art::local_cover_path(&event.metadata, &self.cover_directory, "notification_cover"),
+            false => None
+        };
+        let icon = cover.map(|path| path.display().to_string()).unwrap_or_default();
+
+        let mut hints: HashMap<&str, arg::Variant<Box<dyn arg::RefArg>>> = HashMap::new();
+        if icon != "" {
+            hints.insert("image-path", arg::Variant(Box::new(icon.clone())));
+        }
+        hints.insert("category", arg::Variant(Box::new("x-gnome.music".to_string())));
+
+        //Connect to D-Bus and set operating location
+        let session = Connection::new_session()?;
+        let proxy = session.with_proxy("org.freedesktop.Notifications", "/org/freedesktop/Notifications", Duration::from_millis(5000));
+        let actions: Vec<&str> = Vec::new();
+        let (id,): (u32,) = proxy.method_call(
+            "org.freedesktop.Notifications",
+            "Notify",
+            ("1Lt-NowPlaying", self.replaces_id, icon.as_str(), summary.as_str(), body.as_str(), actions, hints, self.timeout_ms)
+        )?;
+        self.replaces_id = id;
+        Ok(())
+    }
+}
@@ -1,113 +1,667 @@
+use std::fs;
 use std::{thread, time};
 use std::sync::{mpsc, Arc, Mutex};
+use std::sync::atomic::{AtomicBool, Ordering};
 use std::sync::mpsc::{Sender, Receiver};
+use std::thread::JoinHandle;
 
-use crate::web_display::WebDisplay;
+use log::{debug, error, info, warn};
+use signal_hook::consts::{SIGHUP, SIGINT, SIGTERM};
+use serde::Serialize;
+
+use crate::history::{self, PlayHistory};
+use crate::hooks::HookRunner;
+use crate::webhooks::Webhook;
+use crate::mqtt::MqttPublisher;
+use crate::obs::ObsOutput;
+use crate::discord::DiscordPresence;
+use crate::irc::IrcBot;
+use crate::scrobbler::Scrobbler;
+use crate::notifications::DesktopNotifications;
+use crate::dbus_service::DbusService;
+use crate::control_socket::ControlSocket;
+use crate::bar::{BarFormat, BarOutput};
+use crate::web_display::{self, WebDisplay, WebDisplayControl};
 use crate::WorkingEnvironment;
 use crate::mpris_mediaplayer2;
-use crate::config::Config;
+use crate::config::{Config, WebhookConfig};
 
+//how long outputs get to send their last update (e.g. clearing Discord or OBS) before exiting
+const SHUTDOWN_TIMEOUT: time::Duration = time::Duration::from_secs(3);
 
 pub struct Service {
     sleep_duration: time::Duration,
-    work_env: WorkingEnvironment,
+    //None in bar mode, nothing is written to disk there
+    work_env: Option<WorkingEnvironment>,
     display_artist: bool,
     display_album: bool,
     display_title: bool,
-    web_display: WebDisplay
+    web_display: Option<WebDisplay>,
+    state: SharedNowplayingData,
+    history: Option<PlayHistory>,
+    dbus_service: Option<DbusService>,
+    control_socket: Option<ControlSocket>,
+    bar: Option<BarOutput>,
+    event_senders: Vec<Sender<TrackEvent>>,
+    output_threads: Vec<JoinHandle<()>>,
+    //hooks, webhooks, MQTT, OBS, Discord, IRC, scrobblers and notifications, restarted when their settings change
+    track_outputs: Vec<TrackOutput>,
+    //the playback position is a D-Bus call, only made for outputs that show it (Discord, status bar)
+    position_needed: bool,
+    //set by SIGINT/SIGTERM, the polling loop ends at its next iteration
+    shutdown: Arc<AtomicBool>,
+    //the running web display, replaced when its address changes
+    web_control: Option<WebDisplayControl>,
+    //the applied config and the file it is reloaded from (None in bar mode)
+    config: Config,
+    config_path: Option<String>,
+    config_modified: Option<time::SystemTime>,
+    //set by SIGHUP, reloads the config at the next iteration
+    reload: Arc<AtomicBool>,
+    //profile chosen with --profile or at runtime, None uses profile from the config file
+    profile: Option<String>
 }
 
+//Current state, shared with the web display and other readers
+#[derive(Clone, Serialize)]
 pub struct NowplayingData {
-    pub current_title: String,
-    pub current_artist: String,
-    pub current_album: String
+    pub player: String,
+    //"playing", "paused" or "stopped"
+    pub status: String,
+    pub metadata: mpris_mediaplayer2::PlayerMetadata,
+    pub previous: mpris_mediaplayer2::PlayerMetadata,
+    //player forced over D-Bus, only this player is watched while it is running
+    pub selected_player: Option<String>,
+    //active profile (empty for none), the profiles of the config file
+    //and a profile requested over D-Bus or HTTP that is applied at the next iteration ("" for none)
+    pub profile: String,
+    pub profiles: Vec<String>,
+    pub requested_profile: Option<String>
+}
+
+pub type SharedNowplayingData = Arc<Mutex<NowplayingData>>;
+
+impl NowplayingData {
+    pub fn new() -> NowplayingData {
+        NowplayingData {
+            player: String::new(),
+            status: TrackEventKind::Stopped.status().to_string(),
+            metadata: mpris_mediaplayer2::PlayerMetadata::new(),
+            previous: mpris_mediaplayer2::PlayerMetadata::new(),
+            selected_player: None,
+            profile: String::new(),
+            profiles: Vec::new(),
+            requested_profile: None
+        }
+    }
+}
+
+#[derive(Clone, Copy, PartialEq, Serialize)]
+#[serde(rename_all = "lowercase")]
+pub enum TrackEventKind {
+    Changed,
+    Paused,
+    Resumed,
+    Stopped
+}
+
+//Sent to every output thread whenever the playing track or playback state changes
+#[derive(Clone, Serialize)]
+pub struct TrackEvent {
+    #[serde(rename = "event")]
+    pub kind: TrackEventKind,
+    pub player: String,
+    pub timestamp: u64,
+    //playback position when the event was sent, only queried while Discord or the status bar runs (0 otherwise)
+    pub position_ms: u64,
+    pub metadata: mpris_mediaplayer2::PlayerMetadata
+}
+
+impl TrackEventKind {
+    pub fn name(&self) -> &'static str {
+        match self {
+            TrackEventKind::Changed => "changed",
+            TrackEventKind::Paused => "paused",
+            TrackEventKind::Resumed => "resumed",
+            TrackEventKind::Stopped => "stopped"
+        }
+    }
+
+    //playback status after the event
+    pub fn status(&self) -> &'static str {
+        match self {
+            TrackEventKind::Changed | TrackEventKind::Resumed => "playing",
+            TrackEventKind::Paused => "paused",
+            TrackEventKind::Stopped => "stopped"
+        }
+    }
+}
+
+//A running output of track_outputs
+struct TrackOutput {
+    kind: TrackOutputKind,
+    sender: Sender<TrackEvent>,
+    thread: JoinHandle<()>
+}
+
+#[derive(Clone, PartialEq)]
+enum TrackOutputKind {
+    Hooks,
+    Webhook(WebhookConfig),
+    Mqtt,
+    Obs,
+    Discord,
+    Irc,
+    LastFm,
+    ListenBrainz,
+    Notifications
+}
+
+impl TrackOutputKind {
+    //the outputs the config turns on, in the order they are started
+    fn configured(config: &Config) -> Vec<TrackOutputKind> {
+        let mut kinds = Vec::new();
+        if !config.hooks.commands.is_empty() {
+            kinds.push(TrackOutputKind::Hooks);
+        }
+        kinds.extend(config.webhooks.iter().cloned().map(TrackOutputKind::Webhook));
+        let enabled = [
+            (config.mqtt.enabled, TrackOutputKind::Mqtt),
+            (config.obs.enabled, TrackOutputKind::Obs),
+            (config.discord.enabled, TrackOutputKind::Discord),
+            (config.irc.enabled, TrackOutputKind::Irc),
+            (config.lastfm.enabled, TrackOutputKind::LastFm),
+            (config.listenbrainz.enabled, TrackOutputKind::ListenBrainz),
+            (config.notifications.enabled, TrackOutputKind::Notifications)
+        ];
+        kinds.extend(enabled.into_iter().filter(|(enabled, _kind)| *enabled).map(|(_enabled, kind)| kind));
+        kinds
+    }
+
+    //true if the output started with old would behave the same with new, templates count for the outputs rendering them
+    //a webhook is identified by its settings, a changed one is a different webhook
+    fn unchanged(&self, old: &Config, new: &Config) -> bool {
+        let rendering = |config: &Config| (config.display_artist, config.display_album, config.display_title);
+        match self {
+            TrackOutputKind::Hooks => old.hooks == new.hooks,
+            TrackOutputKind::Webhook(_target) => rendering(old) == rendering(new),
+            TrackOutputKind::Mqtt => old.mqtt == new.mqtt,
+            TrackOutputKind::Obs => old.obs == new.obs && old.template == new.template && rendering(old) == rendering(new),
+            TrackOutputKind::Discord => old.discord == new.discord,
+            TrackOutputKind::Irc => old.irc == new.irc && rendering(old) == rendering(new),
+            //the offline queue is kept in the work directory
+            TrackOutputKind::LastFm => old.lastfm == new.lastfm && old.work_directory() == new.work_directory(),
+            TrackOutputKind::ListenBrainz => old.listenbrainz == new.listenbrainz && old.work_directory() == new.work_directory(),
+            TrackOutputKind::Notifications => old.notifications == new.notifications && rendering(old) == rendering(new)
+        }
+    }
+
+    //outputs that show the current track, a new one has to be told which track is playing
+    fn shows_track(&self) -> bool {
+        matches!(self, TrackOutputKind::Mqtt | TrackOutputKind::Obs | TrackOutputKind::Discord | TrackOutputKind::Irc)
+    }
+
+    fn scrobbles(&self) -> bool {
+        matches!(self, TrackOutputKind::LastFm | TrackOutputKind::ListenBrainz)
+    }
 }
 
 impl Service {
-    pub fn new(config: &Config, work_env: WorkingEnvironment) -> Service {
+    pub fn new(config: &Config, work_env: WorkingEnvironment, config_path: String, profile: Option<String>) -> Service {
         let sleep_duration = config.sleep_duration;
         let display_artist = config.display_artist;
         let display_album = config.display_album;
         let display_title = config.display_title;
-        let web_display = WebDisplay::new(config.port.clone(), config.web_files.clone(), config.public.clone());
+        let state = Arc::new(Mutex::new(NowplayingData::new()));
+        state.lock().unwrap().profile = config.profile.clone();
+        state.lock().unwrap().profiles = config.profiles.clone();
+        let web_display = WebDisplay::new(config.port.clone(), config.web_files.clone(), config.web_theme.clone(), config.public.clone(), PlayHistory::new(config, &work_env.path_work_directory), Arc::clone(&state));
+        let history = PlayHistory::new(config, &work_env.path_work_directory);
+        let dbus_service = if config.dbus_enabled { Some(DbusService::new(config, Arc::clone(&state))) } else { None };
+        let control_socket = if config.socket_enabled { Some(ControlSocket::new(config, Arc::clone(&state), PlayHistory::new(config, &work_env.path_work_directory))) } else { None };
         Service {
             sleep_duration,
-            work_env,
+            work_env: Some(work_env),
             display_artist,
             display_album,
             display_title,
-            web_display
+            web_display: Some(web_display),
+            state,
+            history: Some(history),
+            dbus_service,
+            control_socket,
+            bar: None,
+            event_senders: Vec::new(),
+            output_threads: Vec::new(),
+            track_outputs: Vec::new(),
+            position_needed: false,
+            shutdown: Arc::new(AtomicBool::new(false)),
+            web_control: None,
+            config: config.clone(),
+            config_modified: config_modified(&config_path),
+            config_path: Some(config_path),
+            reload: Arc::new(AtomicBool::new(false)),
+            profile
         }
     }
 
+    //Only prints the now playing line for a status bar, without web server, file output or other outputs
+    pub fn new_bar(config: &Config, format: BarFormat) -> Service {
+        Service {
+            sleep_duration: config.sleep_duration,
+            work_env: None,
+            display_artist: config.display_artist,
+            display_album: config.display_album,
+            display_title: config.display_title,
+            web_display: None,
+            state: Arc::new(Mutex::new(NowplayingData::new())),
+            history: None,
+            dbus_service: None,
+            control_socket: None,
+            bar: Some(BarOutput::new(config, format)),
+            event_senders: Vec::new(),
+            output_threads: Vec::new(),
+            track_outputs: Vec::new(),
+            position_needed: false,
+            shutdown: Arc::new(AtomicBool::new(false)),
+            web_control: None,
+            config: config.clone(),
+            config_path: None,
+            config_modified: None,
+            reload: Arc::new(AtomicBool::new(false)),
+            profile: None
+        }
+    }
+
+    //Runs until SIGINT or SIGTERM, then shuts the outputs down and returns
     pub fn start(self) {
+        for signal in [SIGINT, SIGTERM] {
+            //a second signal while shutting down exits immediately
+            let registered = signal_hook::flag::register_conditional_shutdown(signal, 1, Arc::clone(&self.shutdown))
+                .and_then(|_id| signal_hook::flag::register(signal, Arc::clone(&self.shutdown)));
+            if let Err(error) = registered {
+                warn!("Cannot register signal handler, no graceful shutdown: {}", error);
+            }
+        }
+        if self.config_path.is_some() {
+            if let Err(error) = signal_hook::flag::register(SIGHUP, Arc::clone(&self.reload)) {
+                warn!("Cannot register SIGHUP handler, the config is only reloaded when the file changes: {}", error);
+            }
+        }
         self.now_playing()
     }
 
     fn now_playing(mut self) {
         let mut old_metadata = mpris_mediaplayer2::PlayerMetadata::new();
-        let (tx, rx): (Sender<NowplayingData>, Receiver<NowplayingData>) = mpsc::channel();
-        let rx = Arc::new(Mutex::new(rx));
-        let rx_web = Arc::clone(&rx);
-        thread::spawn(move || self.web_display.start(rx_web));
+        self.web_control = self.web_display.take().map(WebDisplay::spawn);
+        //the status bar mode only prints
+        if self.bar.is_none() {
+            self.start_track_outputs();
+        }
+        if let Some(dbus_service) = self.dbus_service.take() {
+            self.add_output(move |rx| dbus_service.start(rx));
+        }
+        if let Some(control_socket) = self.control_socket.take() {
+            self.add_output(move |rx| control_socket.start(rx));
+        }
+        if let Some(bar) = self.bar.take() {
+            self.position_needed = true;
+            self.add_output(move |rx| bar.start(rx));
+        }
 
-        loop {
+        //player that was playing during the last iteration, used to detect pause and stop
+        let mut active_player: Option<String> = None;
+        while !self.shutdown.load(Ordering::Relaxed) {
+            let requested_profile = self.state.lock().unwrap().requested_profile.take();
+            if let Some(profile) = requested_profile {
+                self.switch_profile(profile);
+            } else if self.config_changed() {
+                self.reload_config();
+            }
             let mediaplayers =  Service::get_names_of_mediaplayers();
-            //get playback status of detected mediaplayers
-            for mediaplayer in &mediaplayers {
-                let playing = match mpris_mediaplayer2::get_media_player_playback_status(&mediaplayer) {
-                    Ok(pbs) => pbs,
-                    Err(error) => panic!("Error while getting mediaplayer playback status: {:?}", error)
+            let selected_player = self.state.lock().unwrap().selected_player.clone();
+            let playing_player = match select_player(&mediaplayers, selected_player.as_deref(), &self.config.player_priority) {
+                Ok(playing_player) => playing_player,
+                Err(error) => panic!("Error while getting mediaplayer playback status: {:?}", error)
+            };
+            //Get playback metadata if mediaplayer is playing
+            if let Some(mediaplayer) = &playing_player {
+                let metadata = mpris_mediaplayer2::PlayerMetadata::new();
+                let metadata = match metadata.update_metadata_of_player(mediaplayer.to_string()) {
+                    Ok(new_metadata) => new_metadata,
+                    Err(error) => panic!("Error while getting mediaplayer metadata: {:?}", error)
                 };
-                //Get playback metadata if mediaplayer is playing
-                if playing {
-                    let metadata = mpris_mediaplayer2::PlayerMetadata::new();
-                    let metadata = match metadata.update_metadata_of_player(mediaplayer.to_string()) {
-                        Ok(new_metadata) => new_metadata,
-                        Err(error) => panic!("Error while getting mediaplayer metadata: {:?}", error)
-                    };
-                    if old_metadata != metadata {
-                        let _ = rx.lock().unwrap().try_recv();
-                        
-                        match tx.send(NowplayingData{current_artist: metadata.artist.clone(), current_title: metadata.title.clone(), current_album: metadata.album.clone()}) {
-                            Ok(()) => (),
-                            Err(error) => panic!("cannot send data to http service thread: {error}")
-                        };
+                if old_metadata != metadata {
+                    //hidden fields are only interesting when looking for a problem
+                    let mut shown = Vec::new();
+                    if self.display_artist {
+                        shown.push(format!("artist: {}", metadata.artist.trim()));
+                    } else {
+                        debug!("artist (hidden): {}", metadata.artist);
+                    }
+                    if self.display_title {
+                        shown.push(format!("title: {}", metadata.title));
+                    } else {
+                        debug!("title (hidden): {}", metadata.title);
+                    }
+                    if self.display_album {
+                        shown.push(format!("album: {}", metadata.album));
+                    } else {
+                        debug!("album (hidden): {}", metadata.album);
+                    }
+                    info!("{}: {}", mediaplayer, shown.join(", "));
+                    debug!("metadata of {}: {:?}", mediaplayer, metadata.fields);
 
-                        println!("{}", mediaplayer);
-                        
-                        if self.display_artist {
-                            println!("artist: {}", metadata.artist);
-                        } else {
-                            println!("artist (hidden): {}", metadata.artist);
-                        }
-                        
-                        if self.display_title {
-                            println!("title: {}", metadata.title);
-                        } else {
-                            println!("title (hidden): {}", metadata.title);
-                        }
-
-                        if self.display_album {
-                            println!("album: {}", metadata.album);
-                        } else {
-                            println!("album (hidden): {}", metadata.album);
-                        }
-                        println!("");
-    
-                        self.work_env = match self.work_env.write_to_now_playing_file(&metadata) {
-                            Ok(workenv) => workenv,
+                    if let Some(work_env) = self.work_env.take() {
+                        self.work_env = match work_env.write_to_now_playing_file(mediaplayer, &metadata, &old_metadata) {
+                            Ok(workenv) => Some(workenv),
                             Err(error) => panic!("Cannot write to now_playing.txt: {:?}", error),
                         };
-                        old_metadata = metadata;
                     }
-                    break;
-                } else {
-                    continue;
+                    if let Some(history) = &mut self.history {
+                        match history.track_changed(mediaplayer, &metadata) {
+                            Ok(()) => (),
+                            Err(error) => error!("Cannot write play history: {:?}", error)
+                        };
+                    }
+                    self.send_event(TrackEventKind::Changed, mediaplayer, &metadata);
+                    old_metadata = metadata;
+                } else if active_player.is_none() {
+                    self.send_event(TrackEventKind::Resumed, mediaplayer, &metadata);
                 }
+                active_player = Some(mediaplayer.clone());
+            }
+            let playing_found = playing_player.is_some();
+            if !playing_found {
+                if let Some(player) = active_player.take() {
+                    let kind = match mpris_mediaplayer2::get_media_player_playback_status_text(&player) {
+                        Ok(status) if status == "Paused" => TrackEventKind::Paused,
+                        _ => TrackEventKind::Stopped
+                    };
+                    self.send_event(kind, &player, &old_metadata);
+                }
+            }
+            if let Some(history) = &mut self.history {
+                history.update_played_time(playing_found);
             }
             thread::sleep(self.sleep_duration);
         }
+        self.shut_down(&old_metadata);
+    }
+
+    //SIGHUP or a new modification time of the config file
+    fn config_changed(&mut self) -> bool {
+        let config_path = match &self.config_path {
+            Some(config_path) => config_path,
+            None => return false
+        };
+        let modified = config_modified(config_path);
+        let signaled = self.reload.swap(false, Ordering::Relaxed);
+        if modified == self.config_modified && !signaled {
+            return false
+        }
+        self.config_modified = modified;
+        true
+    }
+
+    //Profile requested over D-Bus or HTTP, an empty name returns to the profile of the config file
+    fn switch_profile(&mut self, profile: String) {
+        let previous = self.profile.take();
+        if profile != "" {
+            self.profile = Some(profile);
+        }
+        if !self.reload_config() {
+            self.profile = previous;
+            return
+        }
+        info!("Profile: {}", if self.config.profile == "" { "none" } else { &self.config.profile });
+    }
+
+    //Applies a changed config to the running service, an invalid config is rejected and the current one kept
+    fn reload_config(&mut self) -> bool {
+        let config_path = self.config_path.clone().unwrap_or_default();
+        let (config, report) = Config::check(&config_path, self.profile.as_deref());
+        for warning in &report.warnings {
+            warn!("config: {}", warning);
+        }
+        if !report.errors.is_empty() {
+            for error in &report.errors {
+                error!("config: {}", error);
+            }
+            error!("Config not reloaded, keeping the current one");
+            return false
+        }
+        {
+            let mut state = self.state.lock().unwrap();
+            state.profile = config.profile.clone();
+            state.profiles = config.profiles.clone();
+        }
+        if config == self.config {
+            return true
+        }
+        info!("Reloading config from {}", config_path);
+
+        self.sleep_duration = config.sleep_duration;
+        self.display_artist = config.display_artist;
+        self.display_album = config.display_album;
+        self.display_title = config.display_title;
+
+        //rewrite now_playing.txt so a new template shows up right away
+        if let Some(mut work_env) = self.work_env.take() {
+            work_env.set_config(config.clone());
+            let state = self.state.lock().unwrap().clone();
+            self.work_env = match state.player != "" && state.status != TrackEventKind::Stopped.status() {
+                true => match work_env.write_to_now_playing_file(&state.player, &state.metadata, &state.previous) {
+                    Ok(work_env) => Some(work_env),
+                    Err(error) => panic!("Cannot write to now_playing.txt: {:?}", error)
+                },
+                false => Some(work_env)
+            };
+        }
+
+        //the listener is only rebound when the port or the bind address changed
+        if let Some(web_control) = self.web_control.take() {
+            web_control.set_files(config.web_files.clone());
+            web_control.set_theme(config.web_theme.clone());
+            self.web_control = match web_display::listen_address(&config.port, config.public) == web_control.address() {
+                true => Some(web_control),
+                false => {
+                    web_control.stop();
+                    let work_directory = config.work_directory();
+                    let web_display = WebDisplay::new(config.port.clone(), config.web_files.clone(), config.web_theme.clone(), config.public, PlayHistory::new(&config, &work_directory), Arc::clone(&self.state));
+                    Some(web_display.spawn())
+                }
+            };
+        }
+
+        if restart_needed(&self.config, &config) {
+            warn!("Some of the changed settings only take effect after a restart");
+        }
+        let old_config = std::mem::replace(&mut self.config, config);
+        if self.work_env.is_some() {
+            self.restart_track_outputs(&old_config);
+        }
+        true
+    }
+
+    //Starts the outputs that follow the track and are configured in the current config
+    fn start_track_outputs(&mut self) {
+        self.position_needed = self.config.discord.enabled;
+        for kind in TrackOutputKind::configured(&self.config) {
+            self.start_track_output(kind);
+        }
+    }
+
+    fn start_track_output(&mut self, kind: TrackOutputKind) {
+        let config = &self.config;
+        let output: Box<dyn FnOnce(Receiver<TrackEvent>) + Send> = match &kind {
+            TrackOutputKind::Hooks => {
+                let hooks = HookRunner::new(config);
+                Box::new(move |rx| hooks.start(rx))
+            }
+            TrackOutputKind::Webhook(target) => {
+                let webhook = Webhook::new(target.clone(), config);
+                Box::new(move |rx| webhook.start(rx))
+            }
+            TrackOutputKind::Mqtt => {
+                let mqtt = MqttPublisher::new(config);
+                Box::new(move |rx| mqtt.start(rx))
+            }
+            TrackOutputKind::Obs => {
+                let obs = ObsOutput::new(config);
+                Box::new(move |rx| obs.start(rx))
+            }
+            TrackOutputKind::Discord => {
+                let discord = DiscordPresence::new(config);
+                Box::new(move |rx| discord.start(rx))
+            }
+            TrackOutputKind::Irc => {
+                let irc = IrcBot::new(config, Arc::clone(&self.state));
+                Box::new(move |rx| irc.start(rx))
+            }
+            TrackOutputKind::LastFm => {
+                let scrobbler = Scrobbler::last_fm(config);
+                Box::new(move |rx| scrobbler.start(rx))
+            }
+            TrackOutputKind::ListenBrainz => {
+                let scrobbler = Scrobbler::listen_brainz(config);
+                Box::new(move |rx| scrobbler.start(rx))
+            }
+            TrackOutputKind::Notifications => {
+                let notifications = DesktopNotifications::new(config);
+                Box::new(move |rx| notifications.start(rx))
+            }
+        };
+        let (tx, rx) = mpsc::channel();
+        self.track_outputs.push(TrackOutput { kind, sender: tx, thread: thread::spawn(move || output(rx)) });
+    }
+
+    //Only the outputs whose own settings changed are replaced, the others keep running untouched.
+    //Outputs showing the current track get a stop (Discord and OBS clear) and the new ones start with the track,
+    //scrobblers only get the stop so the track is submitted once, hooks, webhooks and notifications get nothing.
+    fn restart_track_outputs(&mut self, old_config: &Config) {
+        //every configured output keeps a running one of its kind if its settings are unchanged, the rest is replaced
+        let mut replaced = std::mem::take(&mut self.track_outputs);
+        let mut started = Vec::new();
+        for kind in TrackOutputKind::configured(&self.config) {
+            match replaced.iter().position(|output| output.kind == kind && kind.unchanged(old_config, &self.config)) {
+                Some(index) => self.track_outputs.push(replaced.remove(index)),
+                None => started.push(kind)
+            }
+        }
+        if replaced.is_empty() && started.is_empty() {
+            return
+        }
+
+        let state = self.state.lock().unwrap().clone();
+        let playing = state.player != "" && state.status != TrackEventKind::Stopped.status();
+        if playing {
+            let event = self.track_event(TrackEventKind::Stopped, &state.player, &state.metadata);
+            for output in replaced.iter().filter(|output| output.kind.shows_track() || output.kind.scrobbles()) {
+                let _ = output.sender.send(event.clone());
+            }
+        }
+        //closing the channels ends the old threads, MQTT and Discord must disconnect before the new ones connect
+        let (senders, threads): (Vec<Sender<TrackEvent>>, Vec<JoinHandle<()>>) = replaced.into_iter().map(|output| (output.sender, output.thread)).unzip();
+        drop(senders);
+        let deadline = time::Instant::now() + SHUTDOWN_TIMEOUT;
+        while threads.iter().any(|thread| !thread.is_finished()) && time::Instant::now() < deadline {
+            thread::sleep(time::Duration::from_millis(50));
+        }
+
+        self.position_needed = self.config.discord.enabled;
+        let first_started = self.track_outputs.len();
+        for kind in started {
+            self.start_track_output(kind);
+        }
+        info!("Restarted the outputs with changed settings");
+        if playing {
+            let mut event = self.track_event(TrackEventKind::Changed, &state.player, &state.metadata);
+            if self.position_needed {
+                event.position_ms = mpris_mediaplayer2::get_media_player_position(&state.player).unwrap_or(0).max(0) as u64 / 1000;
+            }
+            for output in self.track_outputs[first_started..].iter().filter(|output| output.kind.shows_track()) {
+                let _ = output.sender.send(event.clone());
+            }
+        }
+    }
+
+    //Stops the outputs, flushes the play history and leaves now_playing.txt as configured in on_exit
+    fn shut_down(mut self, last_metadata: &mpris_mediaplayer2::PlayerMetadata) {
+        info!("Shutting down");
+        let (player, status) = {
+            let state = self.state.lock().unwrap();
+            (state.player.clone(), state.status.clone())
+        };
+        if player != "" && status != TrackEventKind::Stopped.status() {
+            self.send_event(TrackEventKind::Stopped, &player, last_metadata);
+        }
+        if let Some(history) = &mut self.history {
+            if let Err(error) = history.finish_current_track() {
+                error!("Cannot write play history: {:?}", error);
+            }
+        }
+        if let Some(work_env) = self.work_env.take() {
+            if let Err(error) = work_env.write_on_exit(&player, last_metadata) {
+                error!("Cannot write to now_playing.txt: {:?}", error);
+            }
+        }
+
+        if let Some(web_control) = self.web_control.take() {
+            web_control.stop();
+        }
+        //closing the channels ends the output threads once they handled the stop event
+        self.event_senders.clear();
+        let track_threads: Vec<JoinHandle<()>> = std::mem::take(&mut self.track_outputs).into_iter().map(|output| output.thread).collect();
+        let deadline = time::Instant::now() + SHUTDOWN_TIMEOUT;
+        while self.output_threads.iter().chain(track_threads.iter()).any(|output| !output.is_finished()) {
+            if time::Instant::now() >= deadline {
+                warn!("Not all outputs stopped within {}ms", SHUTDOWN_TIMEOUT.as_millis());
+                break
+            }
+            thread::sleep(time::Duration::from_millis(50));
+        }
+        log::logger().flush();
+    }
+
+    //Starts an output on its own thread and subscribes it to track events
+    fn add_output<F>(&mut self, output: F) where F: FnOnce(Receiver<TrackEvent>) + Send + 'static {
+        let (tx, rx) = mpsc::channel();
+        self.event_senders.push(tx);
+        self.output_threads.push(thread::spawn(move || output(rx)));
+    }
+
+    fn send_event(&self, kind: TrackEventKind, player: &str, metadata: &mpris_mediaplayer2::PlayerMetadata) {
+        if kind != TrackEventKind::Changed {
+            debug!("{}: {}", kind.name(), player);
+        }
+        {
+            let mut state = self.state.lock().unwrap();
+            if kind == TrackEventKind::Changed {
+                state.previous = std::mem::replace(&mut state.metadata, metadata.clone());
+            }
+            state.player = player.to_string();
+            state.status = kind.status().to_string();
+        }
+
+        let mut event = self.track_event(kind, player, metadata);
+        if event.kind != TrackEventKind::Stopped && self.position_needed {
+            event.position_ms = mpris_mediaplayer2::get_media_player_position(&player.to_string()).unwrap_or(0).max(0) as u64 / 1000;
+        }
+        for sender in self.event_senders.iter().chain(self.track_outputs.iter().map(|output| &output.sender)) {
+            //an output that stopped simply misses the event
+            let _ = sender.send(event.clone());
+        }
+    }
+
+    fn track_event(&self, kind: TrackEventKind, player: &str, metadata: &mpris_mediaplayer2::PlayerMetadata) -> TrackEvent {
+        TrackEvent {
+            kind,
+            player: player.to_string(),
+            timestamp: history::unix_timestamp(),
+            position_ms: 0,
+            metadata: metadata.clone()
+        }
     }
 
     fn get_names_of_mediaplayers() -> Vec<String> {
@@ -117,4 +671,62 @@ impl Service {
         };
         mediaplayers
     }
-}
\ No newline at end of file
+}
+
+fn config_modified(config_path: &str) -> Option<time::SystemTime> {
+    fs::metadata(config_path).and_then(|metadata| metadata.modified()).ok()
+}
+
+//true if the new config differs in more than the settings reload_config applies
+fn restart_needed(old: &Config, new: &Config) -> bool {
+    let mut applied = with_track_outputs(new, old);
+    applied.template = old.template.clone();
+    applied.display_artist = old.display_artist;
+    applied.display_album = old.display_album;
+    applied.display_title = old.display_title;
+    applied.sleep_duration = old.sleep_duration;
+    applied.on_exit = old.on_exit.clone();
+    applied.offline_template = old.offline_template.clone();
+    applied.port = old.port.clone();
+    applied.public = old.public;
+    applied.web_files = old.web_files.clone();
+    applied.web_theme = old.web_theme.clone();
+    applied.player_priority = old.player_priority.clone();
+    applied.profile = old.profile.clone();
+    applied.profiles = old.profiles.clone();
+    applied != *old
+}
+
+//config with the settings of the track outputs taken from source
+fn with_track_outputs(config: &Config, source: &Config) -> Config {
+    let mut config = config.clone();
+    config.hooks = source.hooks.clone();
+    config.webhooks = source.webhooks.clone();
+    config.mqtt = source.mqtt.clone();
+    config.obs = source.obs.clone();
+    config.discord = source.discord.clone();
+    config.irc = source.irc.clone();
+    config.lastfm = source.lastfm.clone();
+    config.listenbrainz = source.listenbrainz.clone();
+    config.notifications = source.notifications.clone();
+    config
+}
+
+//Selection policy: the player selected over D-Bus while it is running, otherwise the first playing player,
+//players listed in player_priority are checked first and in that order
+pub fn select_player(mediaplayers: &[String], selected_player: Option<&str>, player_priority: &[String]) -> Result<Option<String>, Box<dyn std::error::Error>> {
+    let mut candidates: Vec<&String> = match selected_player {
+        Some(selected_player) if mediaplayers.iter().any(|mediaplayer| mediaplayer == selected_player) => {
+            mediaplayers.iter().filter(|mediaplayer| *mediaplayer == selected_player).collect()
+        }
+        _ => mediaplayers.iter().collect()
+    };
+    //stable, unlisted players keep the order of the bus
+    candidates.sort_by_key(|mediaplayer| player_priority.iter().position(|player| *mediaplayer == player || mediaplayer.starts_with(&format!("{player}."))).unwrap_or(player_priority.len()));
+    for mediaplayer in candidates {
+        if mpris_mediaplayer2::get_media_player_playback_status(mediaplayer)? {
+            return Ok(Some(mediaplayer.clone()))
+        }
+    }
+    Ok(None)
+}
@@ -0,0 +1,303 @@
+use std::net::TcpStream;
+use std::path::PathBuf;
+use std::sync::mpsc::Receiver;
+use std::time;
+
+use base64::Engine;
+use base64::engine::general_purpose::STANDARD as BASE64;
+use log::warn;
+use serde_json::{json, Value};
+use sha2::{Digest, Sha256};
+use tungstenite::stream::MaybeTlsStream;
+use tungstenite::{Message, WebSocket};
+
+use crate::config::Config;
+use crate::cover_art;
+use crate::mpris_mediaplayer2::PlayerMetadata;
+use crate::nowplaying::{TrackEvent, TrackEventKind};
+use crate::paths;
+use crate::template;
+
+const RESPONSE_TIMEOUT: time::Duration = time::Duration::from_millis(5000);
+
+type Socket = WebSocket<MaybeTlsStream<TcpStream>>;
+
+//Updates OBS sources through obs-websocket (protocol v5)
+pub struct ObsOutput {
+    url: String,
+    password: String,
+    //(source name, template)
+    text_sources: Vec<(String, String)>,
+    image_source: String,
+    visibility_scene: String,
+    visibility_source: String,
+    config: Config,
+    cover_directory: PathBuf,
+    request_id: u64,
+    cover_index: u8
+}
+
+impl ObsOutput {
+    pub fn new(config: &Config) -> ObsOutput {
+        ObsOutput {
+            url: config.obs.url.clone(),
+            password: config.obs.password.clone(),
+            text_sources: config.obs.text_sources.iter()
+                .map(|(source, source_template)| (source.clone(), ObsOutput::template_or_default(source_template, config)))
+                .collect(),
+            image_source: config.obs.image_source.clone(),
+            visibility_scene: config.obs.visibility_scene.clone(),
+            visibility_source: config.obs.visibility_source.clone(),
+            config: config.clone(),
+            cover_directory: paths::cache_directory(),
+            request_id: 0,
+            cover_index: 0
+        }
+    }
+
+    //sources without their own template use the general template
+    fn template_or_default(source_template: &str, config: &Config) -> String {
+        if source_template != "" {
+            source_template.to_string()
+        } else if config.template != "" {
+            config.template.clone()
+        } else {
+            "{artist} - {title}".to_string()
+        }
+    }
+
+    pub fn start(mut self, rx: Receiver<TrackEvent>) {
+        let mut socket: Option<Socket> = None;
+        let mut previous = PlayerMetadata::new();
+        for event in rx {
+            if socket.is_none() {
+                socket = match self.connect() {
+                    Ok(socket) => Some(socket),
+                    Err(error) => {
+                        warn!("Cannot connect to OBS at {}: {}", self.url, error);
+                        None
+                    }
+                };
+            }
+            if let Some(connection) = &mut socket {
+                if let Err(error) = self.update(connection, &event, &previous) {
+                    warn!("Lost connection to OBS: {}", error);
+                    socket = None;
+                }
+            }
+            if event.kind == TrackEventKind::Changed {
+                previous = event.metadata;
+            }
+        }
+    }
+
+    //Only returns an error if the connection failed, failed requests are just reported
+    fn update(&mut self, socket: &mut Socket, event: &TrackEvent, previous: &PlayerMetadata) -> Result<(), Box<dyn std::error::Error>> {
+        if event.kind == TrackEventKind::Changed {
+            for (source, source_template) in self.text_sources.clone() {
+                let text = template::render(&source_template, &self.config, &event.player, &event.metadata, previous);
+                self.try_request(socket, "SetInputSettings", json!({"inputName": source, "inputSettings": {"text": text}}))?;
+            }
+            if self.image_source != "" {
+                //alternate between two files, OBS does not reload an unchanged path
+                self.cover_index = 1 - self.cover_index;
+                let file_name = format!("obs_cover_{}", self.cover_index);
+                let file = match cover_art::local_cover_path(&event.metadata, &self.cover_directory, &file_name) {
+                    Some(path) => path.display().to_string(),
+                    None => String::new()
+                };
+                let image_source = self.image_source.clone();
+                self.try_request(socket, "SetInputSettings", json!({"inputName": image_source, "inputSettings": {"file": file}}))?;
+            }
+        }
+
+        if self.visibility_scene != "" && self.visibility_source != "" {
+            let visible = match event.kind {
+                TrackEventKind::Changed | TrackEventKind::Resumed => true,
+                TrackEventKind::Paused | TrackEventKind::Stopped => false
+            };
+            let (scene, source) = (self.visibility_scene.clone(), self.visibility_source.clone());
+            if let Some(item) = self.try_request(socket, "GetSceneItemId", json!({"sceneName": scene, "sourceName": source}))? {
+                let item_id = item["sceneItemId"].clone();
+                self.try_request(socket, "SetSceneItemEnabled", json!({"sceneName": scene, "sceneItemId": item_id, "sceneItemEnabled": visible}))?;
+            }
+        }
+        Ok(())
+    }
+
+    fn connect(&self) -> Result<Socket, Box<dyn std::error::Error>> {
+        let (mut socket, _response) = tungstenite::connect(self.url.as_str())?;
+        if let MaybeTlsStream::Plain(stream) = socket.get_ref() {
+            stream.set_read_timeout(Some(RESPONSE_TIMEOUT))?;
+        }
+
+        //Hello (op 0) -> Identify (op 1) -> Identified (op 2)
+        let hello = read_json(&mut socket)?;
+        if hello["op"] != 0 {
+            return Err("OBS did not send Hello".into())
+        }
+        let mut identify = json!({"rpcVersion": 1, "eventSubscriptions": 0});
+        let authentication = &hello["d"]["authentication"];
+        if authentication.is_object() {
+            let challenge = authentication["challenge"].as_str().unwrap_or_default();
+            let salt = authentication["salt"].as_str().unwrap_or_default();
+            identify["authentication"] = json!(authentication_string(&self.password, salt, challenge));
+        }
+        socket.send(Message::Text(json!({"op": 1, "d": identify}).to_string()))?;
+
+        let identified = read_json(&mut socket)?;
+        if identified["op"] != 2 {
+            return Err("OBS rejected the identification (wrong password?)".into())
+        }
+        Ok(socket)
+    }
+
+    //Like request, but a request rejected by OBS is reported and returns None
+    fn try_request(&mut self, socket: &mut Socket, request_type: &str, request_data: Value) -> Result<Option<Value>, Box<dyn std::error::Error>> {
+        match self.request(socket, request_type, request_data) {
+            Ok(response) => Ok(Some(response)),
+            Err(error) if error.downcast_ref::<tungstenite::Error>().is_some() => Err(error),
+            Err(error) => {
+                warn!("OBS request {}", error);
+                Ok(None)
+            }
+        }
+    }
+
+    //Sends a request (op 6) and returns the responseData of the matching response (op 7)
+    fn request(&mut self, socket: &mut Socket, request_type: &str, request_data: Value) -> Result<Value, Box<dyn std::error::Error>> {
+        self.request_id += 1;
+        let request_id = self.request_id.to_string();
+        let request = json!({"op": 6, "d": {"requestType": request_type, "requestId": request_id, "requestData": request_data}});
+        socket.send(Message::Text(request.to_string()))?;
+
+        loop {
+            let response = read_json(socket)?;
+            if response["op"] != 7 || response["d"]["requestId"] != request_id.as_str() {
+                continue;
+            }
+            let status = &response["d"]["requestStatus"];
+            if status["result"] != true {
+                return Err(format!("{} failed: {}", request_type, status["comment"].as_str().unwrap_or("unknown error")).into())
+            }
+            return Ok(response["d"]["responseData"].clone())
+        }
+    }
+}
+
+fn read_json(socket: &mut Socket) -> Result<Value, Box<dyn std::error::Error>> {
+    loop {
+        match socket.read()? {
+            Message::Text(text) => return Ok(serde_json::from_str(&text)?),
+            Message::Close(_frame) => return Err(tungstenite::Error::ConnectionClosed.into()),
+            _ => continue
+        }
+    }
+}
+
+//base64(sha256(base64(sha256(password + salt)) + challenge))
+fn authentication_string(password: &str, salt: &str, challenge: &str) -> String {
+    let secret = BASE64.encode(Sha256::digest(format!("{password}{salt}")));
+    BASE64.encode(Sha256::digest(format!("{secret}{challenge}")))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::io::{Read, Write};
+    use std::net::TcpListener;
+    use std::sync::mpsc;
+    use std::thread;
+
+    //stand-in for obs-websocket: requires the password, answers every request and returns them
+    fn obs_server(password: &'static str) -> (String, thread::JoinHandle<Vec<Value>>) {
+        let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+        let url = format!("ws://{}", listener.local_addr().unwrap());
+        let handle = thread::spawn(move || {
+            let (stream, _address) = listener.accept().unwrap();
+            let mut socket = tungstenite::accept(stream).unwrap();
+            let hello = json!({"op": 0, "d": {"rpcVersion": 1, "authentication": {"challenge": "challenge", "salt": "salt"}}});
+            socket.send(Message::Text(hello.to_string())).unwrap();
+            let identify: Value = match socket.read().unwrap() {
+                Message::Text(text) => serde_json::from_str(&text).unwrap(),
+                message => panic!("unexpected message {message:?}")
+            };
+            assert_eq!(identify["d"]["authentication"], authentication_string(password, "salt", "challenge"));
+            socket.send(Message::Text(json!({"op": 2, "d": {"negotiatedRpcVersion": 1}}).to_string())).unwrap();
+
+            let mut requests = Vec::new();
+            loop {
+                let request: Value = match socket.read() {
+                    Ok(Message::Text(text)) => serde_json::from_str(&text).unwrap(),
+                    Ok(_message) => continue,
+                    Err(_error) => return requests
+                };
+                let response_data = match request["d"]["requestType"].as_str() {
+                    Some("GetSceneItemId") => json!({"sceneItemId": 7}),
+                    _ => json!({})
+                };
+                let response = json!({"op": 7, "d": {
+                    "requestType": request["d"]["requestType"],
+                    "requestId": request["d"]["requestId"],
+                    "requestStatus": {"result": true, "code": 100},
+                    "responseData": response_data
+                }});
+                socket.send(Message::Text(response.to_string())).unwrap();
+                requests.push(request["d"].clone());
+            }
+        });
+        (url, handle)
+    }
+
+    //serves one cover image over HTTP
+    fn cover_server() -> String {
+        let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+        let url = format!("http://{}/cover.png", listener.local_addr().unwrap());
+        thread::spawn(move || {
+            let (mut stream, _address) = listener.accept().unwrap();
+            let mut request = [0u8; 1024];
+            let _ = stream.read(&mut request);
+            write!(stream, "HTTP/1.1 200 OK\r\nContent-Length: 5\r\nConnection: close\r\n\r\ncover").unwrap();
+        });
+        url
+    }
+
+    fn event(kind: TrackEventKind, metadata: &PlayerMetadata) -> TrackEvent {
+        TrackEvent { kind, player: "vlc".to_string(), timestamp: 1, position_ms: 0, metadata: metadata.clone() }
+    }
+
+    #[test]
+    fn updates_sources_of_a_stand_in_server() {
+        let (url, server) = obs_server("secret");
+        let mut config = Config::defaults();
+        config.obs.url = url;
+        config.obs.password = "secret".to_string();
+        config.obs.text_sources = vec![("Song".to_string(), "{artist} - {title}".to_string())];
+        config.obs.image_source = "Cover".to_string();
+        config.obs.visibility_scene = "Scene".to_string();
+        config.obs.visibility_source = "Overlay".to_string();
+        let cover_directory = std::env::temp_dir().join(format!("nowplaying_obs_test_{}", std::process::id()));
+        let mut output = ObsOutput::new(&config);
+        output.cover_directory = cover_directory.clone();
+
+        let mut metadata = PlayerMetadata::new();
+        metadata.artist = "Artist".to_string();
+        metadata.title = "Title".to_string();
+        metadata.fields.insert("mpris:artUrl".to_string(), cover_server());
+        let (tx, rx) = mpsc::channel();
+        tx.send(event(TrackEventKind::Changed, &metadata)).unwrap();
+        tx.send(event(TrackEventKind::Paused, &metadata)).unwrap();
+        drop(tx);
+        output.start(rx);
+
+        let requests = server.join().unwrap();
+        let types: Vec<&str> = requests.iter().map(|request| request["requestType"].as_str().unwrap()).collect();
+        assert_eq!(types, ["SetInputSettings", "SetInputSettings", "GetSceneItemId", "SetSceneItemEnabled", "GetSceneItemId", "SetSceneItemEnabled"]);
+        assert_eq!(requests[0]["requestData"], json!({"inputName": "Song", "inputSettings": {"text": "Artist - Title"}}));
+        let cover = requests[1]["requestData"]["inputSettings"]["file"].as_str().unwrap();
+        assert_eq!(std::fs::read_to_string(cover).unwrap(), "cover");
+        assert_eq!(requests[3]["requestData"]["sceneItemEnabled"], true);
+        assert_eq!(requests[5]["requestData"]["sceneItemEnabled"], false);
+        let _ = std::fs::remove_dir_all(cover_directory);
+    }
+}
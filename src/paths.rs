@@ -0,0 +1,74 @@
+use std::env;
+use std::path::PathBuf;
+use std::process;
+use std::sync::OnceLock;
+
+use crate::cli::EXIT_ERROR;
+
+const APP_DIRECTORY: &str = "1lt_software/1lt_nowplaying";
+//environment variables that are not config keys
+pub const ENV_CONFIG: &str = "NOWPLAYING_CONFIG";
+pub const ENV_DATA_DIR: &str = "NOWPLAYING_DATA_DIR";
+
+//--data-dir, set once before the config is read
+static DATA_DIRECTORY: OnceLock<PathBuf> = OnceLock::new();
+
+pub fn set_data_directory(path: &str) {
+    let _ = DATA_DIRECTORY.set(PathBuf::from(path));
+}
+
+//--data-dir or $NOWPLAYING_DATA_DIR, replaces the directory of nowplaying_path
+pub fn data_directory_override() -> Option<PathBuf> {
+    match DATA_DIRECTORY.get() {
+        Some(path) => Some(path.clone()),
+        None => env::var(ENV_DATA_DIR).ok().filter(|path| path != "").map(PathBuf::from)
+    }
+}
+
+//$XDG_CONFIG_HOME/1lt_software/1lt_nowplaying, default ~/.config/...
+pub fn config_directory() -> PathBuf {
+    xdg_directory("XDG_CONFIG_HOME", ".config").join(APP_DIRECTORY)
+}
+
+//now_playing.txt, the play history and the web display files, --data-dir or the default below
+pub fn data_directory() -> PathBuf {
+    match data_directory_override() {
+        Some(path) => path,
+        None => default_data_directory()
+    }
+}
+
+//$XDG_DATA_HOME/1lt_software/1lt_nowplaying, default ~/.local/share/...
+//the config defaults use this one, the override is only applied when the config is read
+pub fn default_data_directory() -> PathBuf {
+    xdg_directory("XDG_DATA_HOME", ".local/share").join(APP_DIRECTORY)
+}
+
+//downloaded cover art, $XDG_CACHE_HOME/1lt_software/1lt_nowplaying, default ~/.cache/...
+pub fn cache_directory() -> PathBuf {
+    xdg_directory("XDG_CACHE_HOME", ".cache").join(APP_DIRECTORY)
+}
+
+//sockets, $XDG_RUNTIME_DIR or the temp directory
+pub fn runtime_directory() -> PathBuf {
+    match env::var("XDG_RUNTIME_DIR") {
+        Ok(directory) if directory != "" => PathBuf::from(directory),
+        _ => env::temp_dir()
+    }
+}
+
+//the spec says relative paths in XDG_* variables are invalid and must be ignored
+fn xdg_directory(variable: &str, home_fallback: &str) -> PathBuf {
+    if let Ok(directory) = env::var(variable) {
+        if PathBuf::from(&directory).is_absolute() {
+            return PathBuf::from(directory)
+        }
+    }
+    match env::var("HOME") {
+        Ok(home) if home != "" => PathBuf::from(home).join(home_fallback),
+        _ => {
+            eprintln!("Neither ${variable} nor $HOME is set, use --config and --data-dir to choose the directories");
+            process::exit(EXIT_ERROR);
+        }
+    }
+}
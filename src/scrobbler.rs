@@ -0,0 +1,561 @@
+use std::fs;
+use std::io::prelude::*;
+use std::path::PathBuf;
+use std::sync::mpsc::{Receiver, RecvTimeoutError};
+use std::time;
+
+use log::{error, warn};
+use md5::{Digest, Md5};
+use serde::{Deserialize, Serialize};
+use serde_json::{json, Value};
+
+use crate::config::Config;
+use crate::nowplaying::{TrackEvent, TrackEventKind};
+
+const CHECK_INTERVAL: time::Duration = time::Duration::from_secs(5);
+const RETRY_INTERVAL: time::Duration = time::Duration::from_secs(60);
+const REQUEST_TIMEOUT: time::Duration = time::Duration::from_secs(10);
+//scrobbling rules: at least half of the track or 4 minutes, tracks must be longer than 30 seconds
+const MIN_TRACK_LENGTH_MS: u64 = 30 * 1000;
+const MAX_REQUIRED_PLAY_MS: u64 = 4 * 60 * 1000;
+
+#[derive(Clone, Serialize, Deserialize)]
+struct Scrobble {
+    started: u64,
+    player: String,
+    artist: String,
+    title: String,
+    album: String,
+    length_ms: u64
+}
+
+enum ScrobbleApi {
+    LastFm {
+        base_url: String,
+        api_key: String,
+        api_secret: String,
+        session_key: String,
+        username: String,
+        password: String
+    },
+    ListenBrainz {
+        base_url: String,
+        token: String
+    }
+}
+
+enum SubmitError {
+    //endpoint not reachable or temporarily failing, the scrobble is queued
+    Unreachable(String),
+    //the service refused the request, retrying would not help
+    Rejected(String)
+}
+
+//track that is currently playing and the time it was played so far
+struct PlayState {
+    scrobble: Scrobble,
+    played: time::Duration,
+    playing_since: Option<time::Instant>,
+    scrobbled: bool
+}
+
+impl PlayState {
+    fn played_ms(&self) -> u64 {
+        let running = self.playing_since.map_or(time::Duration::ZERO, |since| since.elapsed());
+        (self.played + running).as_millis() as u64
+    }
+
+    fn pause(&mut self) {
+        if let Some(since) = self.playing_since.take() {
+            self.played += since.elapsed();
+        }
+    }
+
+    fn should_scrobble(&self) -> bool {
+        let length_ms = self.scrobble.length_ms;
+        if self.scrobbled || (length_ms > 0 && length_ms <= MIN_TRACK_LENGTH_MS) {
+            return false
+        }
+        //without a known length only the 4 minute rule applies
+        let required_ms = if length_ms > 0 { (length_ms / 2).min(MAX_REQUIRED_PLAY_MS) } else { MAX_REQUIRED_PLAY_MS };
+        self.played_ms() >= required_ms
+    }
+}
+
+//Scrobbles played tracks to a Last.fm or ListenBrainz compatible service
+pub struct Scrobbler {
+    api: ScrobbleApi,
+    agent: ureq::Agent,
+    //scrobbles that could not be submitted yet, one JSON object per line
+    queue_path: PathBuf
+}
+
+impl Scrobbler {
+    fn new(api: ScrobbleApi, config: &Config) -> Scrobbler {
+        let queue_name = match api {
+            ScrobbleApi::LastFm { .. } => "scrobble_queue_lastfm.jsonl",
+            ScrobbleApi::ListenBrainz { .. } => "scrobble_queue_listenbrainz.jsonl"
+        };
+        Scrobbler {
+            api,
+            agent: ureq::AgentBuilder::new().timeout(REQUEST_TIMEOUT).build(),
+            queue_path: config.work_directory().join(queue_name)
+        }
+    }
+
+    pub fn last_fm(config: &Config) -> Scrobbler {
+        let api = ScrobbleApi::LastFm {
+            base_url: config.lastfm.base_url.clone(),
+            api_key: config.lastfm.api_key.clone(),
+            api_secret: config.lastfm.api_secret.clone(),
+            session_key: config.lastfm.session_key.clone(),
+            username: config.lastfm.username.clone(),
+            password: config.lastfm.password.clone()
+        };
+        Scrobbler::new(api, config)
+    }
+
+    pub fn listen_brainz(config: &Config) -> Scrobbler {
+        let api = ScrobbleApi::ListenBrainz {
+            base_url: config.listenbrainz.base_url.trim_end_matches('/').to_string(),
+            token: config.listenbrainz.token.clone()
+        };
+        Scrobbler::new(api, config)
+    }
+
+    pub fn start(mut self, rx: Receiver<TrackEvent>) {
+        let mut current: Option<PlayState> = None;
+        let mut last_retry = time::Instant::now();
+        self.retry_queue();
+        loop {
+            match rx.recv_timeout(CHECK_INTERVAL) {
+                Ok(event) => match event.kind {
+                    TrackEventKind::Changed => {
+                        let scrobble = Scrobble {
+                            started: event.timestamp,
+                            player: event.player.clone(),
+                            artist: event.metadata.artist.trim().to_string(),
+                            title: event.metadata.title.clone(),
+                            album: event.metadata.album.clone(),
+                            length_ms: event.metadata.length_ms
+                        };
+                        self.now_playing(&scrobble);
+                        current = Some(PlayState { scrobble, played: time::Duration::ZERO, playing_since: Some(time::Instant::now()), scrobbled: false });
+                    }
+                    TrackEventKind::Resumed => {
+                        if let Some(state) = &mut current {
+                            state.playing_since = Some(time::Instant::now());
+                            let scrobble = state.scrobble.clone();
+                            self.now_playing(&scrobble);
+                        }
+                    }
+                    TrackEventKind::Paused | TrackEventKind::Stopped => {
+                        if let Some(state) = &mut current {
+                            state.pause();
+                        }
+                    }
+                },
+                Err(RecvTimeoutError::Timeout) => (),
+                Err(RecvTimeoutError::Disconnected) => return
+            }
+
+            if let Some(state) = &mut current {
+                if state.should_scrobble() {
+                    state.scrobbled = true;
+                    let scrobble = state.scrobble.clone();
+                    self.scrobble(scrobble);
+                }
+            }
+            if last_retry.elapsed() >= RETRY_INTERVAL {
+                last_retry = time::Instant::now();
+                self.retry_queue();
+            }
+        }
+    }
+
+    fn now_playing(&mut self, scrobble: &Scrobble) {
+        if scrobble.artist == "" || scrobble.title == "" {
+            return
+        }
+        let result = match &self.api {
+            ScrobbleApi::LastFm { .. } => self.last_fm_request("track.updateNowPlaying", scrobble_parameters(scrobble, false)).map(|_response| ()),
+            ScrobbleApi::ListenBrainz { .. } => self.submit_listens("playing_now", &[scrobble])
+        };
+        match result {
+            Ok(()) => (),
+            Err(SubmitError::Unreachable(error)) | Err(SubmitError::Rejected(error)) => warn!("Cannot send now playing to {}: {}", self.name(), error)
+        }
+    }
+
+    //Submits the scrobble, or queues it if the service is not reachable
+    fn scrobble(&mut self, scrobble: Scrobble) {
+        if scrobble.artist == "" || scrobble.title == "" {
+            return
+        }
+        //keep the order if older scrobbles are still waiting
+        if self.queue_path.exists() {
+            self.queue(&scrobble);
+            self.retry_queue();
+            return
+        }
+        match self.submit(&scrobble) {
+            Ok(()) => (),
+            Err(SubmitError::Unreachable(error)) => {
+                warn!("Cannot reach {}, queueing scrobble: {}", self.name(), error);
+                self.queue(&scrobble);
+            }
+            Err(SubmitError::Rejected(error)) => warn!("{} rejected scrobble of {} - {}: {}", self.name(), scrobble.artist, scrobble.title, error)
+        }
+    }
+
+    fn submit(&mut self, scrobble: &Scrobble) -> Result<(), SubmitError> {
+        match &self.api {
+            ScrobbleApi::LastFm { .. } => self.last_fm_request("track.scrobble", scrobble_parameters(scrobble, true)).map(|_response| ()),
+            ScrobbleApi::ListenBrainz { .. } => self.submit_listens("single", &[scrobble])
+        }
+    }
+
+    fn queue(&self, scrobble: &Scrobble) {
+        let result = serde_json::to_string(scrobble).map_err(|error| error.to_string()).and_then(|line| {
+            let mut file = fs::OpenOptions::new().create(true).append(true).open(&self.queue_path).map_err(|error| error.to_string())?;
+            writeln!(file, "{}", line).map_err(|error| error.to_string())
+        });
+        if let Err(error) = result {
+            error!("Cannot write scrobble queue {}: {}", self.queue_path.display(), error);
+        }
+    }
+
+    //Submits queued scrobbles in order until the service becomes unreachable again
+    fn retry_queue(&mut self) {
+        let content = match fs::read_to_string(&self.queue_path) {
+            Ok(content) => content,
+            Err(_error) => return
+        };
+        let mut remaining: Vec<&str> = Vec::new();
+        for line in content.lines() {
+            if !remaining.is_empty() {
+                remaining.push(line);
+                continue;
+            }
+            let scrobble: Scrobble = match serde_json::from_str(line) {
+                Ok(scrobble) => scrobble,
+                Err(_error) => continue
+            };
+            match self.submit(&scrobble) {
+                Ok(()) => (),
+                Err(SubmitError::Unreachable(_error)) => remaining.push(line),
+                Err(SubmitError::Rejected(error)) => warn!("{} rejected queued scrobble of {} - {}: {}", self.name(), scrobble.artist, scrobble.title, error)
+            }
+        }
+
+        let result = if remaining.is_empty() {
+            fs::remove_file(&self.queue_path)
+        } else {
+            fs::write(&self.queue_path, remaining.join("\n") + "\n")
+        };
+        if let Err(error) = result {
+            error!("Cannot update scrobble queue {}: {}", self.queue_path.display(), error);
+        }
+    }
+
+    fn name(&self) -> &'static str {
+        match self.api {
+            ScrobbleApi::LastFm { .. } => "Last.fm",
+            ScrobbleApi::ListenBrainz { .. } => "ListenBrainz"
+        }
+    }
+
+    //POST /1/submit-listens with listen_type "single" or "playing_now"
+    fn submit_listens(&self, listen_type: &str, scrobbles: &[&Scrobble]) -> Result<(), SubmitError> {
+        let (base_url, token) = match &self.api {
+            ScrobbleApi::ListenBrainz { base_url, token } => (base_url, token),
+            ScrobbleApi::LastFm { .. } => return Err(SubmitError::Rejected("not a ListenBrainz endpoint".to_string()))
+        };
+        let payload: Vec<Value> = scrobbles.iter().map(|scrobble| {
+            let mut listen = json!({
+                "track_metadata": {
+                    "artist_name": scrobble.artist,
+                    "track_name": scrobble.title,
+                    "additional_info": {
+                        "media_player": scrobble.player,
+                        "submission_client": "1Lt-NowPlaying"
+                    }
+                }
+            });
+            if scrobble.album != "" {
+                listen["track_metadata"]["release_name"] = json!(scrobble.album);
+            }
+            if scrobble.length_ms > 0 {
+                listen["track_metadata"]["additional_info"]["duration_ms"] = json!(scrobble.length_ms);
+            }
+            if listen_type != "playing_now" {
+                listen["listened_at"] = json!(scrobble.started);
+            }
+            listen
+        }).collect();
+
+        let response = self.agent.post(&format!("{base_url}/1/submit-listens"))
+            .set("Authorization", &format!("Token {token}"))
+            .set("Content-Type", "application/json")
+            .send_string(&json!({"listen_type": listen_type, "payload": payload}).to_string());
+        match response {
+            Ok(_response) => Ok(()),
+            Err(ureq::Error::Status(status, response)) => {
+                let body = response_json(response);
+                let message = format!("HTTP {status}: {}", body["error"].as_str().unwrap_or("unknown error"));
+                if status == 429 || status >= 500 {
+                    Err(SubmitError::Unreachable(message))
+                } else {
+                    Err(SubmitError::Rejected(message))
+                }
+            }
+            Err(ureq::Error::Transport(transport)) => Err(SubmitError::Unreachable(transport.to_string()))
+        }
+    }
+
+    //Signed Last.fm API call, requests a session first if only username/password are configured
+    fn last_fm_request(&mut self, method: &str, mut parameters: Vec<(String, String)>) -> Result<Value, SubmitError> {
+        let needs_session = matches!(&self.api, ScrobbleApi::LastFm { session_key, .. } if session_key == "");
+        if needs_session && method != "auth.getMobileSession" {
+            let new_session_key = self.last_fm_session()?;
+            if let ScrobbleApi::LastFm { session_key, .. } = &mut self.api {
+                *session_key = new_session_key;
+            }
+        }
+        let (base_url, api_key, api_secret, session_key) = match &self.api {
+            ScrobbleApi::LastFm { base_url, api_key, api_secret, session_key, .. } => (base_url, api_key, api_secret, session_key),
+            ScrobbleApi::ListenBrainz { .. } => return Err(SubmitError::Rejected("not a Last.fm endpoint".to_string()))
+        };
+
+        parameters.push(("method".to_string(), method.to_string()));
+        parameters.push(("api_key".to_string(), api_key.clone()));
+        if session_key != "" {
+            parameters.push(("sk".to_string(), session_key.clone()));
+        }
+        parameters.push(("api_sig".to_string(), last_fm_signature(&parameters, api_secret)));
+        parameters.push(("format".to_string(), "json".to_string()));
+        let form: Vec<(&str, &str)> = parameters.iter().map(|(name, value)| (name.as_str(), value.as_str())).collect();
+
+        let body: Value = match self.agent.post(base_url).send_form(&form) {
+            Ok(response) => response_json(response),
+            Err(ureq::Error::Status(status, response)) => match response_json(response) {
+                body if body["error"].is_number() => body,
+                _ => return Err(SubmitError::Unreachable(format!("HTTP {status}")))
+            },
+            Err(ureq::Error::Transport(transport)) => return Err(SubmitError::Unreachable(transport.to_string()))
+        };
+        match body["error"].as_u64() {
+            None => Ok(body),
+            Some(code) => {
+                let message = format!("error {code}: {}", body["message"].as_str().unwrap_or("unknown error"));
+                match code {
+                    //operation failed, service offline, temporarily unavailable, rate limit exceeded
+                    8 | 11 | 16 | 29 => Err(SubmitError::Unreachable(message)),
+                    //invalid session key, request a new one next time if username/password are known
+                    9 => {
+                        if let ScrobbleApi::LastFm { session_key, username, .. } = &mut self.api {
+                            if username != "" {
+                                session_key.clear();
+                                return Err(SubmitError::Unreachable(message))
+                            }
+                        }
+                        Err(SubmitError::Rejected(message))
+                    }
+                    _ => Err(SubmitError::Rejected(message))
+                }
+            }
+        }
+    }
+
+    fn last_fm_session(&mut self) -> Result<String, SubmitError> {
+        let (username, password) = match &self.api {
+            ScrobbleApi::LastFm { username, password, .. } => (username.clone(), password.clone()),
+            ScrobbleApi::ListenBrainz { .. } => return Err(SubmitError::Rejected("not a Last.fm endpoint".to_string()))
+        };
+        let parameters = vec![("username".to_string(), username), ("password".to_string(), password)];
+        let response = self.last_fm_request("auth.getMobileSession", parameters)?;
+        match response["session"]["key"].as_str() {
+            Some(key) => Ok(key.to_string()),
+            None => Err(SubmitError::Rejected("Last.fm did not return a session key".to_string()))
+        }
+    }
+}
+
+fn response_json(response: ureq::Response) -> Value {
+    match response.into_string() {
+        Ok(body) => serde_json::from_str(&body).unwrap_or(Value::Null),
+        Err(_error) => Value::Null
+    }
+}
+
+fn scrobble_parameters(scrobble: &Scrobble, with_timestamp: bool) -> Vec<(String, String)> {
+    let mut parameters = vec![
+        ("artist".to_string(), scrobble.artist.clone()),
+        ("track".to_string(), scrobble.title.clone())
+    ];
+    if scrobble.album != "" {
+        parameters.push(("album".to_string(), scrobble.album.clone()));
+    }
+    if scrobble.length_ms > 0 {
+        parameters.push(("duration".to_string(), (scrobble.length_ms / 1000).to_string()));
+    }
+    if with_timestamp {
+        parameters.push(("timestamp".to_string(), scrobble.started.to_string()));
+    }
+    parameters
+}
+
+//md5 of all parameters sorted by name as <name><value>, followed by the API secret
+fn last_fm_signature(parameters: &[(String, String)], api_secret: &str) -> String {
+    let mut sorted: Vec<&(String, String)> = parameters.iter().collect();
+    sorted.sort();
+    let mut signature = String::new();
+    for (name, value) in sorted {
+        signature.push_str(name);
+        signature.push_str(value);
+    }
+    signature.push_str(api_secret);
+    Md5::digest(signature.as_bytes()).iter().map(|byte| format!("{byte:02x}")).collect()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::io::{BufRead, BufReader, Read, Write};
+    use std::net::TcpListener;
+    use std::thread;
+    use crate::web_display::percent_decode;
+
+    //answers the requests with the given status codes and returns the headers and bodies it received
+    fn listener(statuses: Vec<u16>) -> (String, thread::JoinHandle<Vec<(String, String)>>) {
+        let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+        let url = format!("http://{}", listener.local_addr().unwrap());
+        let handle = thread::spawn(move || {
+            let mut requests = Vec::new();
+            for status in statuses {
+                let (mut stream, _address) = listener.accept().unwrap();
+                let mut reader = BufReader::new(stream.try_clone().unwrap());
+                let mut headers = String::new();
+                let mut length = 0;
+                loop {
+                    let mut line = String::new();
+                    reader.read_line(&mut line).unwrap();
+                    if line == "\r\n" {
+                        break
+                    }
+                    if let Some((name, value)) = line.split_once(':') {
+                        if name.eq_ignore_ascii_case("content-length") {
+                            length = value.trim().parse().unwrap();
+                        }
+                    }
+                    headers.push_str(&line);
+                }
+                let mut body = vec![0u8; length];
+                reader.read_exact(&mut body).unwrap();
+                requests.push((headers, String::from_utf8(body).unwrap()));
+                write!(stream, "HTTP/1.1 {status} STATUS\r\nContent-Length: 0\r\nConnection: close\r\n\r\n").unwrap();
+            }
+            requests
+        });
+        (url, handle)
+    }
+
+    //config with its own work directory for the queue files
+    fn test_config(name: &str) -> Config {
+        let work_directory = std::env::temp_dir().join(format!("nowplaying_scrobbler_test_{}_{}", std::process::id(), name));
+        let _ = fs::remove_dir_all(&work_directory);
+        fs::create_dir_all(&work_directory).unwrap();
+        let mut config = Config::defaults();
+        config.nowplaying_path = work_directory.join("nowplaying.txt").to_string_lossy().to_string();
+        config
+    }
+
+    fn scrobble(title: &str) -> Scrobble {
+        Scrobble {
+            started: 1700000000,
+            player: "vlc".to_string(),
+            artist: "Artist".to_string(),
+            title: title.to_string(),
+            album: "Album".to_string(),
+            length_ms: 200 * 1000
+        }
+    }
+
+    fn form(body: &str) -> Vec<(String, String)> {
+        body.split('&').filter_map(|pair| pair.split_once('=')).map(|(name, value)| (percent_decode(name, true), percent_decode(value, true))).collect()
+    }
+
+    #[test]
+    fn last_fm_scrobbles_are_signed() {
+        let (url, handle) = listener(vec![200]);
+        let mut config = test_config("lastfm");
+        config.lastfm.base_url = url;
+        config.lastfm.api_key = "key".to_string();
+        config.lastfm.api_secret = "secret".to_string();
+        config.lastfm.session_key = "session".to_string();
+        let mut scrobbler = Scrobbler::last_fm(&config);
+        assert!(scrobbler.submit(&scrobble("Song Title")).is_ok());
+
+        let requests = handle.join().unwrap();
+        let parameters = form(&requests[0].1);
+        let value = |name: &str| parameters.iter().find(|(parameter, _value)| parameter == name).map(|(_parameter, value)| value.clone()).unwrap_or_default();
+        assert_eq!(value("method"), "track.scrobble");
+        assert_eq!(value("artist"), "Artist");
+        assert_eq!(value("track"), "Song Title");
+        assert_eq!(value("album"), "Album");
+        assert_eq!(value("duration"), "200");
+        assert_eq!(value("timestamp"), "1700000000");
+        assert_eq!(value("sk"), "session");
+        assert_eq!(value("format"), "json");
+        let signed = "albumAlbumapi_keykeyartistArtistduration200methodtrack.scrobblesksessiontimestamp1700000000trackSong Titlesecret";
+        let expected: String = Md5::digest(signed.as_bytes()).iter().map(|byte| format!("{byte:02x}")).collect();
+        assert_eq!(value("api_sig"), expected);
+    }
+
+    #[test]
+    fn listen_brainz_payload() {
+        let (url, handle) = listener(vec![200]);
+        let mut config = test_config("listenbrainz");
+        config.listenbrainz.base_url = url + "/";
+        config.listenbrainz.token = "token".to_string();
+        let mut scrobbler = Scrobbler::listen_brainz(&config);
+        assert!(scrobbler.submit(&scrobble("Song")).is_ok());
+
+        let requests = handle.join().unwrap();
+        assert!(requests[0].0.starts_with("POST /1/submit-listens "));
+        assert!(requests[0].0.contains("Authorization: Token token"));
+        let body: Value = serde_json::from_str(&requests[0].1).unwrap();
+        assert_eq!(body["listen_type"], "single");
+        let listen = &body["payload"][0];
+        assert_eq!(listen["listened_at"], 1700000000);
+        assert_eq!(listen["track_metadata"]["artist_name"], "Artist");
+        assert_eq!(listen["track_metadata"]["track_name"], "Song");
+        assert_eq!(listen["track_metadata"]["release_name"], "Album");
+        assert_eq!(listen["track_metadata"]["additional_info"]["duration_ms"], 200000);
+        assert_eq!(listen["track_metadata"]["additional_info"]["media_player"], "vlc");
+    }
+
+    #[test]
+    fn queues_scrobbles_until_the_service_is_reachable() {
+        let (url, handle) = listener(vec![503, 200, 200]);
+        let mut config = test_config("queue");
+        config.listenbrainz.base_url = url;
+        config.listenbrainz.token = "token".to_string();
+        let mut scrobbler = Scrobbler::listen_brainz(&config);
+
+        scrobbler.scrobble(scrobble("First"));
+        let queued = fs::read_to_string(&scrobbler.queue_path).unwrap();
+        assert_eq!(queued.lines().count(), 1);
+        assert!(queued.contains("\"title\":\"First\""));
+
+        //a new scrobble goes behind the queued one and the queue is retried in order
+        scrobbler.scrobble(scrobble("Second"));
+        assert!(!scrobbler.queue_path.exists());
+        let titles: Vec<Value> = handle.join().unwrap().iter().map(|(_headers, body)| {
+            let body: Value = serde_json::from_str(body).unwrap();
+            body["payload"][0]["track_metadata"]["track_name"].clone()
+        }).collect();
+        assert_eq!(titles, vec!["First", "First", "Second"]);
+        let _ = fs::remove_dir_all(config.work_directory());
+    }
+}
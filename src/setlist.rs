@@ -0,0 +1,180 @@
+use chrono::{Local, NaiveDateTime, TimeZone};
+
+use crate::history::PlayRecord;
+use crate::mpris_mediaplayer2::PlayerMetadata;
+
+#[derive(PartialEq)]
+pub enum SetlistFormat {
+    Text,
+    Csv,
+    Markdown,
+    YouTube
+}
+
+pub struct SetlistOptions {
+    pub from: u64,
+    pub to: u64,
+    //stream start used for relative timestamps, defaults to the first exported track
+    pub stream_start: Option<u64>,
+    pub format: SetlistFormat
+}
+
+impl SetlistOptions {
+    //Parses: [--from TIME] [--to TIME] [--start TIME] [--format text|csv|markdown|youtube]
+    pub fn from_args(args: &[String]) -> Result<SetlistOptions, String> {
+        let mut options = SetlistOptions {
+            from: 0,
+            to: u64::MAX,
+            stream_start: None,
+            format: SetlistFormat::Text
+        };
+
+        let mut args = args.iter();
+        while let Some(arg) = args.next() {
+            let value = match args.next() {
+                Some(value) => value,
+                None => return Err(format!("missing value for {arg}"))
+            };
+            match arg.as_str() {
+                "--from" => options.from = parse_time(value)?,
+                "--to" => options.to = parse_time(value)?,
+                "--start" => options.stream_start = Some(parse_time(value)?),
+                "--format" => options.format = match value.as_str() {
+                    "text" => SetlistFormat::Text,
+                    "csv" => SetlistFormat::Csv,
+                    "markdown" | "md" => SetlistFormat::Markdown,
+                    "youtube" => SetlistFormat::YouTube,
+                    _ => return Err(format!("unknown setlist format: {value}"))
+                },
+                _ => return Err(format!("unknown setlist option: {arg}"))
+            }
+        }
+        Ok(options)
+    }
+}
+
+pub fn export(records: &[PlayRecord], options: &SetlistOptions) -> String {
+    let records: Vec<&PlayRecord> = records.iter()
+        .filter(|record| record.started >= options.from && record.started <= options.to)
+        .collect();
+    let stream_start = match (options.stream_start, records.first()) {
+        (Some(stream_start), _) => stream_start,
+        (None, Some(first)) => first.started,
+        (None, None) => 0
+    };
+
+    let mut setlist = String::new();
+    match options.format {
+        SetlistFormat::Text => {
+            for record in records {
+                let offset = format_offset(record.started.saturating_sub(stream_start), true);
+                setlist.push_str(&format!("{offset}  {}\n", describe(&record.metadata)));
+            }
+        }
+        SetlistFormat::Csv => {
+            setlist.push_str("time,offset,player,artist,title,album,played_seconds\n");
+            for record in records {
+                let fields = [
+                    format_local_time(record.started),
+                    format_offset(record.started.saturating_sub(stream_start), true),
+                    record.player.clone(),
+                    record.metadata.artist.trim().to_string(),
+                    record.metadata.title.clone(),
+                    record.metadata.album.clone(),
+                    (record.played_ms / 1000).to_string()
+                ];
+                let fields: Vec<String> = fields.iter().map(|field| csv_field(field)).collect();
+                setlist.push_str(&fields.join(","));
+                setlist.push('\n');
+            }
+        }
+        SetlistFormat::Markdown => {
+            setlist.push_str("| # | Time | Artist | Title | Album |\n");
+            setlist.push_str("|---|------|--------|-------|-------|\n");
+            for (index, record) in records.iter().enumerate() {
+                setlist.push_str(&format!("| {} | {} | {} | {} | {} |\n",
+                    index + 1,
+                    format_offset(record.started.saturating_sub(stream_start), true),
+                    markdown_field(record.metadata.artist.trim()),
+                    markdown_field(&record.metadata.title),
+                    markdown_field(&record.metadata.album)));
+            }
+        }
+        SetlistFormat::YouTube => {
+            let long_stream = match records.last() {
+                Some(last) => last.started.saturating_sub(stream_start) >= 3600,
+                None => false
+            };
+            //YouTube only accepts chapters if the first one starts at 0:00
+            if let Some(first) = records.first() {
+                if first.started > stream_start {
+                    setlist.push_str(&format!("{} Intro\n", format_offset(0, long_stream)));
+                }
+            }
+            for record in records {
+                let offset = format_offset(record.started.saturating_sub(stream_start), long_stream);
+                setlist.push_str(&format!("{offset} {}\n", describe(&record.metadata)));
+            }
+        }
+    }
+    setlist
+}
+
+fn describe(metadata: &PlayerMetadata) -> String {
+    let artist = metadata.artist.trim();
+    let mut description = if artist != "" {
+        format!("{} - {}", artist, metadata.title)
+    } else {
+        metadata.title.clone()
+    };
+    if metadata.album != "" {
+        description = format!("{} [{}]", description, metadata.album);
+    }
+    description
+}
+
+//Accepts a unix timestamp or a local time as "YYYY-MM-DD HH:MM[:SS]"
+pub fn parse_time(value: &str) -> Result<u64, String> {
+    if let Ok(timestamp) = value.parse::<u64>() {
+        return Ok(timestamp)
+    }
+    let naive = match NaiveDateTime::parse_from_str(value, "%Y-%m-%d %H:%M:%S") {
+        Ok(naive) => naive,
+        Err(_error) => match NaiveDateTime::parse_from_str(value, "%Y-%m-%d %H:%M") {
+            Ok(naive) => naive,
+            Err(_error) => return Err(format!("invalid time \"{value}\", expected \"YYYY-MM-DD HH:MM[:SS]\" or a unix timestamp"))
+        }
+    };
+    match Local.from_local_datetime(&naive).earliest() {
+        Some(time) => Ok(time.timestamp().max(0) as u64),
+        None => Err(format!("time \"{value}\" does not exist in the local time zone"))
+    }
+}
+
+pub fn format_local_time(timestamp: u64) -> String {
+    match Local.timestamp_opt(timestamp as i64, 0).single() {
+        Some(time) => time.format("%Y-%m-%d %H:%M:%S").to_string(),
+        None => timestamp.to_string()
+    }
+}
+
+fn format_offset(seconds: u64, with_hours: bool) -> String {
+    let (hours, minutes, seconds) = (seconds / 3600, seconds / 60 % 60, seconds % 60);
+    if with_hours {
+        format!("{hours}:{minutes:02}:{seconds:02}")
+    } else {
+        format!("{minutes:02}:{seconds:02}")
+    }
+}
+
+fn csv_field(value: &str) -> String {
+    if value.contains(',') || value.contains('"') || value.contains('\n') {
+        format!("\"{}\"", value.replace('"', "\"\""))
+    } else {
+        value.to_string()
+    }
+}
+
+fn markdown_field(value: &str) -> String {
+    value.replace('|', "\\|")
+}
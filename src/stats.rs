@@ -0,0 +1,213 @@
+use std::collections::{BTreeMap, BTreeSet, HashMap};
+
+use chrono::{Local, NaiveDate, TimeZone};
+use serde::Serialize;
+
+use crate::history::PlayRecord;
+use crate::setlist;
+
+const DEFAULT_TOP: usize = 10;
+
+pub struct StatsOptions {
+    pub from: u64,
+    pub to: u64,
+    pub top: usize,
+    pub json: bool
+}
+
+#[derive(Serialize)]
+pub struct ListeningStats {
+    pub plays: usize,
+    pub listening_seconds: u64,
+    pub top_artists: Vec<ArtistCount>,
+    pub top_tracks: Vec<TrackCount>,
+    pub players: Vec<PlayerTime>,
+    pub plays_per_day: Vec<DayCount>,
+    pub longest_streak: Option<Streak>
+}
+
+#[derive(Serialize)]
+pub struct ArtistCount {
+    pub artist: String,
+    pub plays: usize
+}
+
+#[derive(Serialize)]
+pub struct TrackCount {
+    pub artist: String,
+    pub title: String,
+    pub plays: usize
+}
+
+#[derive(Serialize)]
+pub struct PlayerTime {
+    pub player: String,
+    pub listening_seconds: u64
+}
+
+#[derive(Serialize)]
+pub struct DayCount {
+    pub day: String,
+    pub plays: usize
+}
+
+//most consecutive days with at least one play
+#[derive(Serialize)]
+pub struct Streak {
+    pub days: u32,
+    pub first_day: String,
+    pub last_day: String
+}
+
+impl StatsOptions {
+    pub fn new() -> StatsOptions {
+        StatsOptions {
+            from: 0,
+            to: u64::MAX,
+            top: DEFAULT_TOP,
+            json: false
+        }
+    }
+
+    //Parses: [--from TIME] [--to TIME] [--top N] [--json]
+    pub fn from_args(args: &[String]) -> Result<StatsOptions, String> {
+        let mut options = StatsOptions::new();
+        let mut args = args.iter();
+        while let Some(arg) = args.next() {
+            if arg == "--json" {
+                options.json = true;
+                continue;
+            }
+            let value = match args.next() {
+                Some(value) => value,
+                None => return Err(format!("missing value for {arg}"))
+            };
+            match arg.as_str() {
+                "--from" => options.from = setlist::parse_time(value)?,
+                "--to" => options.to = setlist::parse_time(value)?,
+                "--top" => options.top = match value.parse::<usize>() {
+                    Ok(top) => top,
+                    Err(_error) => return Err(format!("--top must be a number: {value}"))
+                },
+                _ => return Err(format!("unknown stats option: {arg}"))
+            }
+        }
+        Ok(options)
+    }
+}
+
+pub fn compute(records: &[PlayRecord], options: &StatsOptions) -> ListeningStats {
+    let records: Vec<&PlayRecord> = records.iter()
+        .filter(|record| record.started >= options.from && record.started <= options.to)
+        .collect();
+
+    let mut artists: HashMap<String, usize> = HashMap::new();
+    let mut tracks: HashMap<(String, String), usize> = HashMap::new();
+    let mut players: HashMap<String, u64> = HashMap::new();
+    let mut days: BTreeMap<NaiveDate, usize> = BTreeMap::new();
+    let mut listening_ms = 0;
+
+    for record in &records {
+        let artist = record.metadata.artist.trim().to_string();
+        if artist != "" {
+            *artists.entry(artist.clone()).or_insert(0) += 1;
+        }
+        *tracks.entry((artist, record.metadata.title.clone())).or_insert(0) += 1;
+        *players.entry(record.player.clone()).or_insert(0) += record.played_ms;
+        if let Some(day) = local_day(record.started) {
+            *days.entry(day).or_insert(0) += 1;
+        }
+        listening_ms += record.played_ms;
+    }
+
+    let mut top_artists: Vec<ArtistCount> = artists.into_iter()
+        .map(|(artist, plays)| ArtistCount { artist, plays })
+        .collect();
+    top_artists.sort_by(|a, b| b.plays.cmp(&a.plays).then(a.artist.cmp(&b.artist)));
+    top_artists.truncate(options.top);
+
+    let mut top_tracks: Vec<TrackCount> = tracks.into_iter()
+        .map(|((artist, title), plays)| TrackCount { artist, title, plays })
+        .collect();
+    top_tracks.sort_by(|a, b| b.plays.cmp(&a.plays).then(a.artist.cmp(&b.artist)).then(a.title.cmp(&b.title)));
+    top_tracks.truncate(options.top);
+
+    let mut players: Vec<PlayerTime> = players.into_iter()
+        .map(|(player, played_ms)| PlayerTime { player, listening_seconds: played_ms / 1000 })
+        .collect();
+    players.sort_by(|a, b| b.listening_seconds.cmp(&a.listening_seconds));
+
+    let longest_streak = longest_streak(&days.keys().cloned().collect());
+    let plays_per_day = days.into_iter()
+        .map(|(day, plays)| DayCount { day: day.to_string(), plays })
+        .collect();
+
+    ListeningStats {
+        plays: records.len(),
+        listening_seconds: listening_ms / 1000,
+        top_artists,
+        top_tracks,
+        players,
+        plays_per_day,
+        longest_streak
+    }
+}
+
+pub fn format_text(stats: &ListeningStats) -> String {
+    let mut text = format!("plays: {}\nlistening time: {}\n", stats.plays, format_duration(stats.listening_seconds));
+
+    text.push_str("\ntop artists:\n");
+    for (index, artist) in stats.top_artists.iter().enumerate() {
+        text.push_str(&format!("{:>3}. {} ({} plays)\n", index + 1, artist.artist, artist.plays));
+    }
+
+    text.push_str("\ntop tracks:\n");
+    for (index, track) in stats.top_tracks.iter().enumerate() {
+        text.push_str(&format!("{:>3}. {} - {} ({} plays)\n", index + 1, track.artist, track.title, track.plays));
+    }
+
+    text.push_str("\nlistening time per player:\n");
+    for player in &stats.players {
+        text.push_str(&format!("     {}: {}\n", player.player, format_duration(player.listening_seconds)));
+    }
+
+    text.push_str("\nplays per day:\n");
+    for day in &stats.plays_per_day {
+        text.push_str(&format!("     {}: {}\n", day.day, day.plays));
+    }
+
+    if let Some(streak) = &stats.longest_streak {
+        text.push_str(&format!("\nlongest streak: {} days ({} to {})\n", streak.days, streak.first_day, streak.last_day));
+    }
+    text
+}
+
+fn longest_streak(days: &BTreeSet<NaiveDate>) -> Option<Streak> {
+    let mut longest: Option<(NaiveDate, NaiveDate, u32)> = None;
+    let mut current: Option<(NaiveDate, NaiveDate, u32)> = None;
+
+    for day in days {
+        let streak = match current {
+            Some((first, last, count)) if last.succ_opt() == Some(*day) => (first, *day, count + 1),
+            _ => (*day, *day, 1)
+        };
+        current = Some(streak);
+        let longer = match longest {
+            Some((_, _, count)) => streak.2 > count,
+            None => true
+        };
+        if longer {
+            longest = current;
+        }
+    }
+
+    longest.map(|(first, last, days)| Streak { days, first_day: first.to_string(), last_day: last.to_string() })
+}
+
+fn local_day(timestamp: u64) -> Option<NaiveDate> {
+    Local.timestamp_opt(timestamp as i64, 0).single().map(|time| time.date_naive())
+}
+
+fn format_duration(seconds: u64) -> String {
+    format!("{}h {:02}m", seconds / 3600, seconds / 60 % 60)
+}
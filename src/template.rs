@@ -0,0 +1,130 @@
+use crate::config::Config;
+use crate::mpris_mediaplayer2::PlayerMetadata;
+use crate::nowplaying::TrackEvent;
+
+//Replaces {artist}, {title}, {album}, {player} and {previous} in the template.
+//Fields hidden by the display_* settings are replaced with an empty string.
+pub fn render(template: &str, config: &Config, player: &str, metadata: &PlayerMetadata, previous: &PlayerMetadata) -> String {
+    render_with(template, config, player, metadata, previous, None)
+}
+
+fn render_with(template: &str, config: &Config, player: &str, metadata: &PlayerMetadata, previous: &PlayerMetadata, json: Option<&str>) -> String {
+    let artist = if config.display_artist { metadata.artist.trim() } else { "" };
+    let title = if config.display_title { metadata.title.as_str() } else { "" };
+    let album = if config.display_album { metadata.album.as_str() } else { "" };
+
+    substitute(template, |placeholder| match placeholder {
+        "artist" => Some(artist.to_string()),
+        "title" => Some(title.to_string()),
+        "album" => Some(album.to_string()),
+        "player" => Some(player.to_string()),
+        "previous" => Some(short_description(config, previous)),
+        "json" => json.map(|json| json.to_string()),
+        _ => None
+    })
+}
+
+//Replaces every {name} the lookup knows in one pass, so placeholders inside the inserted values stay as they are
+fn substitute<F>(template: &str, lookup: F) -> String where F: Fn(&str) -> Option<String> {
+    let mut rendered = String::new();
+    let mut rest = template;
+    while let Some(start) = rest.find('{') {
+        rendered.push_str(&rest[..start]);
+        let placeholder = &rest[start..];
+        match placeholder.find('}').and_then(|end| lookup(&placeholder[1..end]).map(|value| (end, value))) {
+            Some((end, value)) => {
+                rendered.push_str(&value);
+                rest = &placeholder[end + 1..];
+            }
+            None => {
+                rendered.push('{');
+                rest = &placeholder[1..];
+            }
+        }
+    }
+    rendered.push_str(rest);
+    rendered
+}
+
+//"artist - title" of a track, used for {previous}
+pub fn short_description(config: &Config, metadata: &PlayerMetadata) -> String {
+    let mut parts = Vec::new();
+    if config.display_artist && metadata.artist.trim() != "" {
+        parts.push(metadata.artist.trim());
+    }
+    if config.display_title && metadata.title != "" {
+        parts.push(metadata.title.as_str());
+    }
+    parts.join(" - ")
+}
+
+//Like render, but escapes the inserted values for use inside JSON strings
+//and additionally replaces {json} with the whole event as JSON
+pub fn render_json(template: &str, config: &Config, event: &TrackEvent, previous: &PlayerMetadata) -> String {
+    let json = serde_json::to_string(event).ok();
+    render_with(template, config, &json_escape(&event.player), &escape_metadata(&event.metadata), &escape_metadata(previous), json.as_deref())
+}
+
+fn escape_metadata(metadata: &PlayerMetadata) -> PlayerMetadata {
+    let mut escaped = metadata.clone();
+    escaped.artist = json_escape(&metadata.artist);
+    escaped.title = json_escape(&metadata.title);
+    escaped.album = json_escape(&metadata.album);
+    escaped
+}
+
+pub fn json_escape(value: &str) -> String {
+    //serde_json always returns a quoted string, strip the surrounding quotes
+    match serde_json::to_string(value) {
+        Ok(quoted) => quoted[1..quoted.len() - 1].to_string(),
+        Err(_error) => String::new()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::nowplaying::TrackEventKind;
+
+    fn metadata(artist: &str, title: &str, album: &str) -> PlayerMetadata {
+        let mut metadata = PlayerMetadata::new();
+        metadata.artist = artist.to_string();
+        metadata.title = title.to_string();
+        metadata.album = album.to_string();
+        metadata
+    }
+
+    fn event(metadata: PlayerMetadata) -> TrackEvent {
+        TrackEvent {
+            kind: TrackEventKind::Changed,
+            player: "spotify".to_string(),
+            timestamp: 0,
+            position_ms: 0,
+            metadata
+        }
+    }
+
+    #[test]
+    fn placeholders_in_values_are_not_replaced() {
+        let config = Config::defaults();
+        let rendered = render("{title} by {artist}", &config, "vlc", &metadata("Band", "Song {artist} {json}", ""), &PlayerMetadata::new());
+        assert_eq!(rendered, "Song {artist} {json} by Band");
+    }
+
+    #[test]
+    fn unknown_placeholders_and_braces_stay() {
+        let config = Config::defaults();
+        let rendered = render("{ {unknown} {player}} {", &config, "vlc", &PlayerMetadata::new(), &PlayerMetadata::new());
+        assert_eq!(rendered, "{ {unknown} vlc} {");
+    }
+
+    #[test]
+    fn json_body_stays_valid() {
+        let config = Config::defaults();
+        let event = event(metadata("A \"quoted\" artist", "{json}", "{title}"));
+        let body = render_json("{\"text\": \"{artist} - {title} [{album}]\", \"event\": {json}}", &config, &event, &PlayerMetadata::new());
+        let parsed: serde_json::Value = serde_json::from_str(&body).unwrap();
+        assert_eq!(parsed["text"], "A \"quoted\" artist - {json} [{title}]");
+        assert_eq!(parsed["event"]["metadata"]["title"], "{json}");
+    }
+}
@@ -1,76 +1,176 @@
 use std::fs::File;
 use std::io::{prelude::*, BufReader};
 use std::net::{TcpListener, TcpStream};
-use std::sync::{mpsc, Arc, Mutex};
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::sync::{Arc, Mutex};
+use std::thread::{self, JoinHandle};
+use std::time;
 
+use log::{debug, error, info, warn};
 
-use crate::config;
-use crate::nowplaying::NowplayingData;
+use crate::history::{PlayHistory, PlayRecord};
+use crate::nowplaying::SharedNowplayingData;
+use crate::paths;
+use crate::setlist;
+use crate::stats;
+
+const DEFAULT_HISTORY_LIMIT: usize = 5;
+//requests are handled one after another, an idle client must not block the listener
+const CLIENT_TIMEOUT: time::Duration = time::Duration::from_millis(2000);
+//stop waits this long for the listener to finish its request, then leaves the thread behind
+const STOP_TIMEOUT: time::Duration = time::Duration::from_millis(3000);
 
 
 pub struct WebDisplay{
-    port: String,
-    files:  Vec<String>,
-    public: bool
+    address: String,
+    files:  Arc<Mutex<Vec<String>>>,
+    theme: Arc<Mutex<String>>,
+    history: PlayHistory,
+    state: SharedNowplayingData,
+    stop: Arc<AtomicBool>
+}
+
+//Handle of a running web display, used to change it after a config reload
+pub struct WebDisplayControl {
+    address: String,
+    files: Arc<Mutex<Vec<String>>>,
+    theme: Arc<Mutex<String>>,
+    stop: Arc<AtomicBool>,
+    thread: JoinHandle<()>
 }
 
 impl WebDisplay {
-    pub fn new(port: String, files: Vec<String>, public: bool) -> WebDisplay{
-        let port_check: u64 = match port.parse() {
-            Ok(port_check) => port_check,
-            Err(_error) => panic!("config error: provided port is not a valid number")
-        };
-        if !(port_check >= 1024 && port_check <= 65535 || port_check == 80) {
-            panic!("config error: provided port is not within accepted range (80 or 1024-65535)");
+    //the port is validated when the config is read
+    pub fn new(port: String, files: Vec<String>, theme: String, public: bool, history: PlayHistory, state: SharedNowplayingData) -> WebDisplay{
+        WebDisplay{
+            address: listen_address(&port, public),
+            files: Arc::new(Mutex::new(files)),
+            theme: Arc::new(Mutex::new(theme)),
+            history,
+            state,
+            stop: Arc::new(AtomicBool::new(false))
         }
+    }
 
-        WebDisplay{port, files, public}
+    pub fn spawn(self) -> WebDisplayControl {
+        WebDisplayControl {
+            address: self.address.clone(),
+            files: Arc::clone(&self.files),
+            theme: Arc::clone(&self.theme),
+            stop: Arc::clone(&self.stop),
+            thread: thread::spawn(move || self.start())
+        }
     }
 
-    pub fn start(&self, rx: Arc<Mutex<mpsc::Receiver<NowplayingData>>>) {
-        let listener = match TcpListener::bind("127.0.0.1:9500") {
+    fn start(&self) {
+        let listener = match TcpListener::bind(&self.address) {
             Ok(listener) => listener,
-            Err(error) => panic!("Connont bind tcp listener to 127.0.0.1:9500 {}", error)
+            Err(error) => {
+                error!("Cannot bind tcp listener to {}: {}", self.address, error);
+                return
+            }
         };
+        info!("web display listening on {}", self.address);
 
-        let mut old_data = NowplayingData{current_artist: String::new(), current_title: String::new(), current_album: String::new()};
         for stream in listener.incoming() {
+            if self.stop.load(Ordering::Relaxed) {
+                return
+            }
             match stream {
-                Ok(stream) => old_data = WebDisplay::handle_http_request(&self, stream, &rx, old_data),
+                Ok(stream) => WebDisplay::handle_http_request(&self, stream),
                 Err(error) => panic!("Cannot handle tcp stream: {error}")
             };
         }
     }
 
-    fn handle_http_request(&self, mut stream: TcpStream, rx: &Arc<Mutex<mpsc::Receiver<NowplayingData>>>, old_data: NowplayingData) -> NowplayingData{
+    fn handle_http_request(&self, mut stream: TcpStream) {
+        if stream.set_read_timeout(Some(CLIENT_TIMEOUT)).and_then(|()| stream.set_write_timeout(Some(CLIENT_TIMEOUT))).is_err() {
+            return
+        }
         let buf_reader = BufReader::new(&mut stream);
         let request = match buf_reader.lines().next() {
             Some(request) => match request {
                 Ok(request) => request,
-                Err(_error) => return old_data//ignore request if faulty
+                Err(_error) => return//ignore request if faulty
             }
-            None => return old_data//ignore request if faulty
+            None => return//ignore request if faulty
         };
-        let mut current_data = old_data;
         let mut status_line = String::new();
         let mut content: Vec<u8> = Vec::new();
 
-        match &request[..] {
-            "GET /nowplaying HTTP/1.1" => {
-                current_data = WebDisplay::get_nowplaying_data(rx, current_data);
-                if current_data.current_album != String::new() {
+        //split "GET /path?query HTTP/1.1" into method, path and query, only /profile takes a POST
+        let (method, target) = match request.split_once(' ').and_then(|(method, rest)| rest.strip_suffix(" HTTP/1.1").map(|target| (method, target))) {
+            Some(("POST", target)) if target == "/profile" || target.starts_with("/profile?") => ("POST", target),
+            Some(("GET", target)) => ("GET", target),
+            _ => ("", "")
+        };
+        let (path, query) = match target.split_once('?') {
+            Some((path, query)) => (path, query),
+            None => (target, "")
+        };
+
+        match path {
+            "/nowplaying" => {
+                let current_data = self.state.lock().unwrap().metadata.clone();
+                if current_data.album != String::new() {
                     status_line = "HTTP/1.1 200 OK".to_owned(); 
-                    content = format!("{{\"nowplaying\": {{\"title\": \"{} [{}]\", \"artist\": \"{}\"}}}}", current_data.current_title, current_data.current_album, current_data.current_artist).as_bytes().to_vec();
+                    content = format!("{{\"nowplaying\": {{\"title\": \"{} [{}]\", \"artist\": \"{}\"}}}}", current_data.title, current_data.album, current_data.artist).as_bytes().to_vec();
                 }
                 else {
                     status_line = "HTTP/1.1 200 OK".to_owned();
-                    content = format!("{{\"nowplaying\": {{\"title\": \"{}\", \"artist\": \"{}\"}}}}", current_data.current_title, current_data.current_artist).as_bytes().to_vec();
+                    content = format!("{{\"nowplaying\": {{\"title\": \"{}\", \"artist\": \"{}\"}}}}", current_data.title, current_data.artist).as_bytes().to_vec();
                 }
             }
+            "/history" => {
+                let limit = match WebDisplay::get_query_value(query, "limit") {
+                    Some(limit) => limit.parse::<usize>().ok().filter(|limit| *limit > 0),
+                    None => Some(DEFAULT_HISTORY_LIMIT)
+                };
+                (status_line, content) = match limit {
+                    Some(limit) => WebDisplay::history_response(self.history.last_records(limit)),
+                    None => WebDisplay::bad_request_response("limit must be a positive number")
+                };
+            }
+            "/history/since" => {
+                let since = match WebDisplay::get_query_value(query, "ts") {
+                    Some(since) => since.parse::<u64>().ok(),
+                    None => None
+                };
+                (status_line, content) = match since {
+                    Some(since) => WebDisplay::history_response(self.history.records_since(since)),
+                    None => WebDisplay::bad_request_response("ts must be a unix timestamp in seconds")
+                };
+            }
+            "/stats" => {
+                (status_line, content) = match WebDisplay::stats_options(query) {
+                    Ok(options) => match self.history.records() {
+                        Ok(records) => match serde_json::to_string(&stats::compute(&records, &options)) {
+                            Ok(json) => ("HTTP/1.1 200 OK\r\nContent-Type: application/json".to_owned(), json.as_bytes().to_vec()),
+                            Err(_error) => ("HTTP/1.1 500 INTERNAL SERVER ERROR".to_owned(), Vec::new())
+                        },
+                        Err(error) => {
+                            error!("Cannot read play history: {:?}", error);
+                            ("HTTP/1.1 500 INTERNAL SERVER ERROR".to_owned(), Vec::new())
+                        }
+                    },
+                    Err(error) => WebDisplay::bad_request_response(&error)
+                };
+            }
+            //switching changes the service, so only a POST from this machine may do it (no links or <img> from other pages)
+            "/profile" => {
+                let local = stream.peer_addr().map(|address| address.ip().is_loopback()).unwrap_or(false);
+                (status_line, content) = match (method, WebDisplay::get_query_value(query, "name")) {
+                    ("POST", Some(_name)) if !local => ("HTTP/1.1 403 FORBIDDEN\r\nContent-Type: application/json".to_owned(), serde_json::json!({"error": "profiles can only be switched from this machine"}).to_string().as_bytes().to_vec()),
+                    ("POST", Some(name)) => self.select_profile(&percent_decode(name, true)),
+                    ("POST", None) => WebDisplay::bad_request_response("name is missing"),
+                    (_method, Some(_name)) => ("HTTP/1.1 405 METHOD NOT ALLOWED\r\nAllow: POST\r\nContent-Type: application/json".to_owned(), serde_json::json!({"error": "use POST to switch the profile"}).to_string().as_bytes().to_vec()),
+                    (_method, None) => self.profile_response()
+                };
+            }
             _ => {
-                for file in &self.files {
-                    if &request[..] == format!("GET {file} HTTP/1.1") {
-                        match WebDisplay::get_file_binary(file.clone()) {
+                for file in self.files.lock().unwrap().iter() {
+                    if path == file {
+                        match self.get_file_binary(file.clone()) {
                             Ok(file_content) => {status_line = "HTTP/1.1 200 OK".to_owned(); content = file_content;},
                             Err(()) => {status_line = "HTTP/1.1 404 NOT FOUND".to_owned(); content = String::new().as_bytes().to_vec();}
                         };
@@ -92,29 +192,174 @@ impl WebDisplay {
         response.extend(content);
         match stream.write_all(response.as_slice()) {
             Ok(()) => (),
-            Err(error) => panic!("error while answering http request: {error}")
+            //a client that stopped reading only loses its own answer
+            Err(error) => debug!("error while answering http request: {error}")
+        };
+    }
+
+    fn history_response(records: Result<Vec<PlayRecord>, std::io::Error>) -> (String, Vec<u8>) {
+        let records = match records {
+            Ok(records) => records,
+            Err(error) => {
+                error!("Cannot read play history: {:?}", error);
+                return ("HTTP/1.1 500 INTERNAL SERVER ERROR".to_owned(), Vec::new())
+            }
         };
+        match serde_json::to_string(&records) {
+            Ok(json) => (
+                "HTTP/1.1 200 OK\r\nContent-Type: application/json".to_owned(),
+                format!("{{\"history\": {json}}}").as_bytes().to_vec()
+            ),
+            Err(_error) => ("HTTP/1.1 500 INTERNAL SERVER ERROR".to_owned(), Vec::new())
+        }
+    }
+
+    //query parameters: from, to (unix timestamp or local time) and top
+    fn stats_options(query: &str) -> Result<stats::StatsOptions, String> {
+        let mut options = stats::StatsOptions::new();
+        if let Some(from) = WebDisplay::get_query_value(query, "from") {
+            options.from = setlist::parse_time(&percent_decode(from, true))?;
+        }
+        if let Some(to) = WebDisplay::get_query_value(query, "to") {
+            options.to = setlist::parse_time(&percent_decode(to, true))?;
+        }
+        if let Some(top) = WebDisplay::get_query_value(query, "top") {
+            options.top = match top.parse::<usize>() {
+                Ok(top) => top,
+                Err(_error) => return Err("top must be a positive number".to_string())
+            };
+        }
+        Ok(options)
+    }
+
+    //active profile and the profiles of the config file
+    fn profile_response(&self) -> (String, Vec<u8>) {
+        let state = self.state.lock().unwrap();
+        let json = serde_json::json!({"profile": state.profile, "profiles": state.profiles});
+        ("HTTP/1.1 200 OK\r\nContent-Type: application/json".to_owned(), json.to_string().as_bytes().to_vec())
+    }
+
+    //the service switches at its next iteration, an empty name returns to the profile of the config file
+    fn select_profile(&self, name: &str) -> (String, Vec<u8>) {
+        let mut state = self.state.lock().unwrap();
+        if name != "" && !state.profiles.iter().any(|profile| profile == name) {
+            return WebDisplay::bad_request_response("unknown profile")
+        }
+        state.requested_profile = Some(name.to_string());
+        let json = serde_json::json!({"requested": name});
+        ("HTTP/1.1 202 ACCEPTED\r\nContent-Type: application/json".to_owned(), json.to_string().as_bytes().to_vec())
+    }
 
-        current_data
+    fn bad_request_response(message: &str) -> (String, Vec<u8>) {
+        let json = serde_json::json!({"error": message});
+        ("HTTP/1.1 400 BAD REQUEST\r\nContent-Type: application/json".to_owned(), json.to_string().as_bytes().to_vec())
     }
 
-    fn get_nowplaying_data(rx: &Arc<Mutex<mpsc::Receiver<NowplayingData>>>, mut current_data: NowplayingData) -> NowplayingData {
-        for new_data in rx.lock().unwrap().try_iter() {
-            current_data = new_data
+    fn get_query_value<'a>(query: &'a str, key: &str) -> Option<&'a str> {
+        for pair in query.split('&') {
+            if let Some((pair_key, value)) = pair.split_once('=') {
+                if pair_key == key {
+                    return Some(value)
+                }
+            }
         }
-        current_data
+        None
     }
 
-    fn get_file_binary(mut filepath: String) -> Result<Vec<u8>, ()> {
-        filepath = format!("{}web_display{filepath}", config::get_default_work_directory());
-        println!("accessed from network: {filepath}");
-        let file = match File::open(filepath) {
-            Ok(file) => file,
-            Err(_error) => return Err(())
+    //a file of the theme replaces the one in web_display
+    fn get_file_binary(&self, filepath: String) -> Result<Vec<u8>, ()> {
+        let theme = self.theme.lock().unwrap().clone();
+        let data_directory = paths::data_directory().display().to_string();
+        let mut paths = Vec::new();
+        if theme != "" {
+            paths.push(format!("{data_directory}/web_display/themes/{theme}{filepath}"));
+        }
+        paths.push(format!("{data_directory}/web_display{filepath}"));
+        let file = match paths.iter().find_map(|path| File::open(path).ok().map(|file| (path, file))) {
+            Some((path, file)) => {
+                debug!("accessed from network: {path}");
+                file
+            }
+            None => return Err(())
         };
         let mut reader = BufReader::new(file);
         let mut content:Vec<u8> = Vec::new();
         let _ = reader.read_to_end(&mut content);
         return Ok(content)
     }
-}
\ No newline at end of file
+}
+
+impl WebDisplayControl {
+    pub fn address(&self) -> &str {
+        &self.address
+    }
+
+    pub fn set_files(&self, files: Vec<String>) {
+        *self.files.lock().unwrap() = files;
+    }
+
+    pub fn set_theme(&self, theme: String) {
+        *self.theme.lock().unwrap() = theme;
+    }
+
+    //Closes the listener and waits until the port is free again, at most STOP_TIMEOUT
+    pub fn stop(self) {
+        self.stop.store(true, Ordering::Relaxed);
+        //accept only returns for a new connection, so make one
+        if let Ok(address) = self.address.replace("0.0.0.0", "127.0.0.1").parse() {
+            let _ = TcpStream::connect_timeout(&address, STOP_TIMEOUT);
+        }
+        let deadline = time::Instant::now() + STOP_TIMEOUT;
+        while !self.thread.is_finished() {
+            if time::Instant::now() >= deadline {
+                warn!("web display on {} did not stop in time", self.address);
+                return
+            }
+            thread::sleep(time::Duration::from_millis(20));
+        }
+        let _ = self.thread.join();
+    }
+}
+
+//public: all interfaces, otherwise only this machine
+pub fn listen_address(port: &str, public: bool) -> String {
+    match public {
+        true => format!("0.0.0.0:{port}"),
+        false => format!("127.0.0.1:{port}")
+    }
+}
+
+//decodes "%XX" escapes, in query values "+" is a space, in file URLs it stays
+pub fn percent_decode(value: &str, plus_as_space: bool) -> String {
+    let bytes = value.as_bytes();
+    let mut decoded: Vec<u8> = Vec::new();
+    let mut index = 0;
+    while index < bytes.len() {
+        let escaped = match bytes.get(index + 1..index + 3) {
+            Some(hex) if bytes[index] == b'%' => std::str::from_utf8(hex).ok().and_then(|hex| u8::from_str_radix(hex, 16).ok()),
+            _ => None
+        };
+        match (bytes[index], escaped) {
+            (_, Some(byte)) => {
+                decoded.push(byte);
+                index += 2;
+            }
+            (b'+', None) if plus_as_space => decoded.push(b' '),
+            (byte, None) => decoded.push(byte)
+        }
+        index += 1;
+    }
+    String::from_utf8_lossy(&decoded).to_string()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn plus_is_a_space_only_in_queries() {
+        assert_eq!(percent_decode("Chill+Out%20%C3%A4", true), "Chill Out ä");
+        assert_eq!(percent_decode("/music/a+b%20c.jpg", false), "/music/a+b c.jpg");
+        assert_eq!(percent_decode("100%", true), "100%");
+    }
+}
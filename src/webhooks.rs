@@ -0,0 +1,158 @@
+use std::sync::mpsc::Receiver;
+use std::{thread, time};
+
+use log::warn;
+
+use crate::config::{Config, WebhookConfig};
+use crate::mpris_mediaplayer2::PlayerMetadata;
+use crate::nowplaying::{TrackEvent, TrackEventKind};
+use crate::template;
+
+const FIRST_RETRY_DELAY: time::Duration = time::Duration::from_millis(1000);
+
+//Posts a JSON body to one webhook target on every track change
+pub struct Webhook {
+    target: WebhookConfig,
+    config: Config,
+    agent: ureq::Agent
+}
+
+impl Webhook {
+    pub fn new(target: WebhookConfig, config: &Config) -> Webhook {
+        let agent = ureq::AgentBuilder::new()
+            .timeout(target.timeout)
+            .build();
+        Webhook {
+            target,
+            config: config.clone(),
+            agent
+        }
+    }
+
+    pub fn start(self, rx: Receiver<TrackEvent>) {
+        let mut previous = PlayerMetadata::new();
+        for event in rx {
+            if event.kind != TrackEventKind::Changed {
+                continue;
+            }
+            let body = template::render_json(&self.target.body, &self.config, &event, &previous);
+            if let Err(error) = self.post_with_retries(&body) {
+                warn!("Cannot send webhook to {}: {}", self.target.url, error);
+            }
+            previous = event.metadata;
+        }
+    }
+
+    fn post_with_retries(&self, body: &str) -> Result<(), ureq::Error> {
+        let mut delay = FIRST_RETRY_DELAY;
+        let mut attempt = 0;
+        loop {
+            let error = match self.post(body) {
+                Ok(()) => return Ok(()),
+                Err(error) => error
+            };
+            //client errors will not go away by retrying, except rate limits
+            let retryable = match &error {
+                ureq::Error::Status(status, _response) => *status == 429 || *status >= 500,
+                ureq::Error::Transport(_transport) => true
+            };
+            if !retryable || attempt >= self.target.retries {
+                return Err(error)
+            }
+            attempt += 1;
+            thread::sleep(delay);
+            delay *= 2;
+        }
+    }
+
+    fn post(&self, body: &str) -> Result<(), ureq::Error> {
+        let mut request = self.agent.post(&self.target.url)
+            .set("Content-Type", "application/json");
+        for (name, value) in &self.target.headers {
+            request = request.set(name, value);
+        }
+        request.send_string(body)?;
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::io::{BufRead, BufReader, Read, Write};
+    use std::net::TcpListener;
+    use std::sync::mpsc;
+
+    //answers the requests with the given status codes and returns the bodies it received
+    fn listener(statuses: Vec<u16>) -> (String, thread::JoinHandle<Vec<(String, String)>>) {
+        let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+        let url = format!("http://{}/hook", listener.local_addr().unwrap());
+        let handle = thread::spawn(move || {
+            let mut requests = Vec::new();
+            for status in statuses {
+                let (mut stream, _address) = listener.accept().unwrap();
+                let mut reader = BufReader::new(stream.try_clone().unwrap());
+                let mut headers = String::new();
+                let mut length = 0;
+                loop {
+                    let mut line = String::new();
+                    reader.read_line(&mut line).unwrap();
+                    if line == "\r\n" {
+                        break
+                    }
+                    if let Some((name, value)) = line.split_once(':') {
+                        if name.eq_ignore_ascii_case("content-length") {
+                            length = value.trim().parse().unwrap();
+                        }
+                    }
+                    headers.push_str(&line);
+                }
+                let mut body = vec![0u8; length];
+                reader.read_exact(&mut body).unwrap();
+                requests.push((headers, String::from_utf8(body).unwrap()));
+                write!(stream, "HTTP/1.1 {status} STATUS\r\nContent-Length: 0\r\nConnection: close\r\n\r\n").unwrap();
+            }
+            requests
+        });
+        (url, handle)
+    }
+
+    fn changed(title: &str) -> TrackEvent {
+        let mut metadata = PlayerMetadata::new();
+        metadata.artist = "Artist".to_string();
+        metadata.title = title.to_string();
+        TrackEvent { kind: TrackEventKind::Changed, player: "vlc".to_string(), timestamp: 1, position_ms: 0, metadata }
+    }
+
+    #[test]
+    fn posts_rendered_body_with_headers() {
+        let (url, handle) = listener(vec![200]);
+        let mut target = WebhookConfig::new(url);
+        target.headers.push(("Authorization".to_string(), "Bearer secret".to_string()));
+        target.body = "{\"content\": \"{artist} - {title}\"}".to_string();
+        let (tx, rx) = mpsc::channel();
+        tx.send(changed("Song \"1\"")).unwrap();
+        drop(tx);
+        Webhook::new(target, &Config::defaults()).start(rx);
+
+        let requests = handle.join().unwrap();
+        assert!(requests[0].0.contains("Authorization: Bearer secret"));
+        let body: serde_json::Value = serde_json::from_str(&requests[0].1).unwrap();
+        assert_eq!(body["content"], "Artist - Song \"1\"");
+    }
+
+    #[test]
+    fn retries_server_errors() {
+        let (url, handle) = listener(vec![500, 200]);
+        let mut target = WebhookConfig::new(url);
+        target.retries = 1;
+        let (tx, rx) = mpsc::channel();
+        tx.send(changed("Song")).unwrap();
+        drop(tx);
+        Webhook::new(target, &Config::defaults()).start(rx);
+
+        let requests = handle.join().unwrap();
+        assert_eq!(requests.len(), 2);
+        assert_eq!(requests[0].1, requests[1].1);
+    }
+}